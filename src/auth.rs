@@ -0,0 +1,289 @@
+//! Signing datagrams with a key both players know, so nobody else can inject or replay
+//! them.
+//!
+//! Every signed datagram ends in a trailer: the sender's ID, the receiver's ID, a counter
+//! the sender increments per datagram, and a MAC of those and the payload. Each side picks
+//! a random ID every run, so the receiver's ID ties a datagram to this match: one recorded
+//! in an earlier match under the same key names an ID nobody uses any more.
+//!
+//! Until a side has heard from the other it doesn't know their ID, and names nobody. Such
+//! a datagram only tells the receiver who to address, and is dropped, so the first few
+//! datagrams each way are lost; GGRS sends them again. After that the receiver drops
+//! datagrams whose MAC doesn't check out as forged, and ones addressed to another ID, whose
+//! counter it has already seen from that sender, or that carry its own ID, as replayed.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::socket::Transport;
+
+/// The sender ID, the receiver ID, the counter and the MAC.
+const TRAILER_LENGTH: usize = 8 + 8 + 8 + MAC_LENGTH;
+/// A truncated BLAKE3 hash is still far beyond guessing within a match.
+const MAC_LENGTH: usize = 16;
+/// How far behind the newest counter from a sender a datagram can arrive and still be
+/// accepted, since UDP can reorder them.
+const REPLAY_WINDOW: u64 = 64;
+/// Named as the receiver by a sender who hasn't heard from them yet. Never anyone's ID.
+const UNKNOWN_ID: u64 = 0;
+/// How many addresses to keep track of: the other player and any spectators, with plenty to
+/// spare. Once there are more, the one heard from least recently is forgotten.
+const MAX_PEERS: usize = 64;
+const PASSPHRASE_CONTEXT: &str = "counter-attack 2023-06 session key from passphrase";
+
+/// Datagrams an [`AuthTransport`] has dropped so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RejectedPackets {
+    pub forged: u64,
+    pub replayed: u64,
+}
+
+/// The 32-byte key both players sign with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SessionKey([u8; 32]);
+
+impl SessionKey {
+    /// A single fast hash of `passphrase`. Anyone who captures one signed datagram can try
+    /// guesses against it offline as fast as they can hash, so only a long, random
+    /// passphrase is as safe as a random key.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self(blake3::derive_key(
+            PASSPHRASE_CONTEXT,
+            passphrase.as_bytes(),
+        ))
+    }
+}
+
+/// Parses 64 hex digits.
+impl FromStr for SessionKey {
+    type Err = String;
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        if hex.len() != 64 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err("expected 64 hex digits".into());
+        }
+        let mut key = [0; 32];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+        }
+        Ok(Self(key))
+    }
+}
+
+/// Keeps the key out of logs.
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+/// Which counters from one sender have been seen.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    newest: u64,
+    /// Bit `n` is set once `newest - n` has been seen.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Whether `counter` is new, remembering it if so.
+    fn check(&mut self, counter: u64) -> bool {
+        if counter > self.newest || self.seen == 0 {
+            let shift = counter - self.newest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.newest = counter;
+            return true;
+        }
+        let age = self.newest - counter;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+/// What is known about whoever signs datagrams from one address.
+struct Peer {
+    /// Their ID, or [`UNKNOWN_ID`].
+    id: u64,
+    /// Whether `id` came from a datagram addressed to this side's ID, which only they can
+    /// have sent this match. Until then a datagram recorded earlier can claim the address.
+    confirmed: bool,
+    window: ReplayWindow,
+    last_heard: Instant,
+}
+
+/// Signs everything sent through `inner` with `key`, and drops anything received that
+/// wasn't signed with it by someone else for this match, or was received before.
+pub struct AuthTransport<T> {
+    inner: T,
+    key: SessionKey,
+    /// Tells this side's datagrams apart from the other's, although they share a key, and
+    /// this match's from earlier ones.
+    id: u64,
+    counter: u64,
+    peers: HashMap<SocketAddr, Peer>,
+    /// Exchanges unsigned datagrams, like the lobby server, which doesn't know the key.
+    unsigned_peer: Option<SocketAddr>,
+    warned: bool,
+    rejected: Arc<Mutex<RejectedPackets>>,
+}
+
+impl<T: Transport> AuthTransport<T> {
+    pub fn new(inner: T, key: SessionKey) -> Self {
+        Self {
+            inner,
+            key,
+            id: RandomState::new()
+                .build_hasher()
+                .finish()
+                .max(UNKNOWN_ID + 1),
+            counter: 0,
+            peers: HashMap::new(),
+            unsigned_peer: None,
+            warned: false,
+            rejected: Arc::default(),
+        }
+    }
+
+    /// Where the counts of dropped datagrams can still be read once the transport has been
+    /// handed to GGRS.
+    pub fn rejected_packets(&self) -> Arc<Mutex<RejectedPackets>> {
+        self.rejected.clone()
+    }
+
+    /// Leaves datagrams to and from `addr` as they are.
+    pub fn with_unsigned_peer(mut self, addr: SocketAddr) -> Self {
+        self.unsigned_peer = Some(addr);
+        self
+    }
+
+    fn mac(&self, signed: &[u8]) -> [u8; MAC_LENGTH] {
+        let hash = blake3::keyed_hash(&self.key.0, signed);
+        hash.as_bytes()[..MAC_LENGTH].try_into().unwrap()
+    }
+
+    /// The length of the payload of `datagram`, if it is signed, new and someone else's,
+    /// and addressed to this side.
+    fn verify(&mut self, datagram: &[u8], src_addr: SocketAddr) -> Option<usize> {
+        let Some(payload_length) = datagram.len().checked_sub(TRAILER_LENGTH) else {
+            return self.reject_forged(src_addr);
+        };
+        let (signed, mac) = datagram.split_at(datagram.len() - MAC_LENGTH);
+        if !constant_time_eq(&self.mac(signed), mac) {
+            return self.reject_forged(src_addr);
+        }
+        let trailer = &signed[payload_length..];
+        let [sender_id, receiver_id, counter] = [0, 8, 16]
+            .map(|start| u64::from_le_bytes(trailer[start..start + 8].try_into().unwrap()));
+        // Our own datagrams bounced back at us are replays too.
+        if sender_id == self.id {
+            return self.reject_replayed();
+        }
+        let id = self.id;
+        let peer = self.peer(src_addr);
+        if !peer.confirmed && peer.id != sender_id {
+            peer.id = sender_id;
+            peer.window = ReplayWindow::default();
+        }
+        if receiver_id == UNKNOWN_ID {
+            // They don't know who we are yet, but can be told now.
+            return None;
+        }
+        if receiver_id != id || sender_id != peer.id {
+            return self.reject_replayed();
+        }
+        peer.confirmed = true;
+        if !peer.window.check(counter) {
+            return self.reject_replayed();
+        }
+        Some(payload_length)
+    }
+
+    /// What is known about `addr`, making room for it if it is new.
+    fn peer(&mut self, addr: SocketAddr) -> &mut Peer {
+        if !self.peers.contains_key(&addr) && self.peers.len() >= MAX_PEERS {
+            let quietest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, peer)| peer.last_heard)
+                .map(|(&addr, _)| addr);
+            self.peers.remove(&quietest.unwrap());
+        }
+        let peer = self.peers.entry(addr).or_insert_with(|| Peer {
+            id: UNKNOWN_ID,
+            confirmed: false,
+            window: ReplayWindow::default(),
+            last_heard: Instant::now(),
+        });
+        peer.last_heard = Instant::now();
+        peer
+    }
+
+    fn reject_forged(&mut self, src_addr: SocketAddr) -> Option<usize> {
+        if !self.warned {
+            eprintln!(
+                "Dropping datagrams from {src_addr} that aren't signed with the session key; \
+                 check both players use the same one"
+            );
+            self.warned = true;
+        }
+        self.rejected.lock().unwrap().forged += 1;
+        None
+    }
+
+    fn reject_replayed(&mut self) -> Option<usize> {
+        self.rejected.lock().unwrap().replayed += 1;
+        None
+    }
+}
+
+impl<T: Transport> Transport for AuthTransport<T> {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if self.unsigned_peer == Some(addr) {
+            return self.inner.send_to(datagram, addr);
+        }
+        let receiver_id = self.peers.get(&addr).map_or(UNKNOWN_ID, |peer| peer.id);
+        let mut signed = Vec::with_capacity(datagram.len() + TRAILER_LENGTH);
+        signed.extend_from_slice(datagram);
+        signed.extend_from_slice(&self.id.to_le_bytes());
+        signed.extend_from_slice(&receiver_id.to_le_bytes());
+        signed.extend_from_slice(&self.counter.to_le_bytes());
+        let mac = self.mac(&signed);
+        signed.extend_from_slice(&mac);
+        self.counter += 1;
+        self.inner.send_to(&signed, addr)
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (number_of_bytes, src_addr) = self.inner.recv_from(buffer)?;
+            if self.unsigned_peer == Some(src_addr) {
+                return Ok((number_of_bytes, src_addr));
+            }
+            if let Some(payload_length) = self.verify(&buffer[..number_of_bytes], src_addr) {
+                return Ok((payload_length, src_addr));
+            }
+        }
+    }
+}
+
+/// Compares MACs without giving away how much of a guess was right through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,213 @@
+//! Signed datagrams between two [`AuthTransport`]s, handed over one at a time so each test
+//! decides what arrives, and how often.
+
+use std::{collections::VecDeque, io::ErrorKind};
+
+use super::*;
+
+type Wire = Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>;
+
+/// Keeps what is sent, and receives what a test puts in its inbox.
+#[derive(Default)]
+struct Mailbox {
+    sent: Wire,
+    inbox: Wire,
+}
+
+impl Transport for Mailbox {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push_back((addr, datagram.to_vec()));
+        Ok(())
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some((src_addr, datagram)) = self.inbox.lock().unwrap().pop_front() else {
+            return Err(ErrorKind::WouldBlock.into());
+        };
+        buffer[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), src_addr))
+    }
+}
+
+/// One side, at `addr`.
+struct Side {
+    addr: SocketAddr,
+    sent: Wire,
+    inbox: Wire,
+    transport: AuthTransport<Mailbox>,
+}
+
+impl Side {
+    fn new(port: u16, key: SessionKey) -> Self {
+        let mailbox = Mailbox::default();
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            sent: mailbox.sent.clone(),
+            inbox: mailbox.inbox.clone(),
+            transport: AuthTransport::new(mailbox, key),
+        }
+    }
+    /// Signs `payload` for `to`, returning the datagram that would go out.
+    fn sign(&mut self, payload: &[u8], to: &Side) -> Vec<u8> {
+        self.transport.send_to(payload, to.addr).unwrap();
+        self.sent.lock().unwrap().pop_back().unwrap().1
+    }
+    /// Delivers `datagram` from `from`, returning the payload if it gets through.
+    fn receive(&mut self, datagram: &[u8], from: &Side) -> Option<Vec<u8>> {
+        self.inbox
+            .lock()
+            .unwrap()
+            .push_back((from.addr, datagram.to_vec()));
+        let mut buffer = [0; 256];
+        match self.transport.recv_from(&mut buffer) {
+            Ok((number_of_bytes, src_addr)) => {
+                assert_eq!(src_addr, from.addr);
+                Some(buffer[..number_of_bytes].to_vec())
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => None,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    fn rejected_packets(&self) -> RejectedPackets {
+        *self.transport.rejected_packets().lock().unwrap()
+    }
+}
+
+fn key() -> SessionKey {
+    SessionKey::from_passphrase("correct horse battery staple")
+}
+
+/// Two sides that have learned each other's IDs, as they do over their first datagrams.
+fn introduced() -> (Side, Side) {
+    let [mut a, mut b] = [7000, 7001].map(|port| Side::new(port, key()));
+    let hello = a.sign(b"hello", &b);
+    assert_eq!(
+        b.receive(&hello, &a),
+        None,
+        "a didn't know who it was talking to"
+    );
+    let reply = b.sign(b"reply", &a);
+    assert_eq!(a.receive(&reply, &b).as_deref(), Some(&b"reply"[..]));
+    (a, b)
+}
+
+#[test]
+fn replay_window_accepts_each_counter_once() {
+    let mut window = ReplayWindow::default();
+    assert!(window.check(0));
+    assert!(window.check(1));
+    assert!(!window.check(1));
+    assert!(!window.check(0));
+}
+
+#[test]
+fn replay_window_accepts_reordering_within_the_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.check(100));
+    assert!(window.check(95));
+    assert!(window.check(100 - (REPLAY_WINDOW - 1)));
+    assert!(!window.check(95));
+    assert!(window.check(97));
+    assert!(window.check(101));
+    assert!(!window.check(97));
+    assert!(!window.check(100));
+}
+
+#[test]
+fn replay_window_drops_counters_from_before_the_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.check(100));
+    assert!(!window.check(100 - REPLAY_WINDOW));
+    assert!(window.check(100 - REPLAY_WINDOW + 1));
+}
+
+#[test]
+fn replay_window_forgets_what_a_long_jump_leaves_behind() {
+    let mut window = ReplayWindow::default();
+    for counter in 0..10 {
+        assert!(window.check(counter));
+    }
+    assert!(window.check(9 + REPLAY_WINDOW));
+    assert!(!window.check(9));
+    assert!(window.check(10));
+    assert!(!window.check(9 + REPLAY_WINDOW));
+
+    assert!(window.check(1000));
+    assert!(!window.check(9 + REPLAY_WINDOW));
+    assert!(window.check(999));
+}
+
+#[test]
+fn signed_datagrams_get_through_once() {
+    let (mut a, mut b) = introduced();
+
+    let datagram = a.sign(b"payload", &b);
+    assert_eq!(b.receive(&datagram, &a).as_deref(), Some(&b"payload"[..]));
+    assert_eq!(b.receive(&datagram, &a), None);
+
+    assert_eq!(
+        b.rejected_packets(),
+        RejectedPackets {
+            forged: 0,
+            replayed: 1
+        }
+    );
+}
+
+#[test]
+fn tampered_and_foreign_datagrams_count_as_forged() {
+    let (mut a, mut b) = introduced();
+
+    let mut tampered = a.sign(b"payload", &b);
+    tampered[0] ^= 1;
+    assert_eq!(b.receive(&tampered, &a), None);
+    let mut stranger = Side::new(a.addr.port(), SessionKey::from_passphrase("guess"));
+    let foreign = stranger.sign(b"payload", &b);
+    assert_eq!(b.receive(&foreign, &a), None);
+    assert_eq!(b.receive(b"short", &a), None);
+
+    assert_eq!(
+        b.rejected_packets(),
+        RejectedPackets {
+            forged: 3,
+            replayed: 0
+        }
+    );
+}
+
+#[test]
+fn datagrams_from_an_earlier_match_count_as_replayed() {
+    let (mut old_a, old_b) = introduced();
+    let recorded = old_a.sign(b"payload", &old_b);
+    let (mut a, mut b) = introduced();
+
+    assert_eq!(b.receive(&recorded, &old_a), None);
+    assert_eq!(b.rejected_packets().replayed, 1);
+    // It didn't disturb this match either.
+    let datagram = a.sign(b"payload", &b);
+    assert_eq!(b.receive(&datagram, &a).as_deref(), Some(&b"payload"[..]));
+}
+
+#[test]
+fn our_own_datagrams_reflected_back_count_as_replayed() {
+    let (mut a, b) = introduced();
+
+    let datagram = a.sign(b"payload", &b);
+    assert_eq!(a.receive(&datagram, &b), None);
+
+    assert_eq!(a.rejected_packets().replayed, 1);
+}
+
+#[test]
+fn each_transport_keeps_its_own_counts() {
+    let (mut a, mut b) = introduced();
+
+    let datagram = a.sign(b"payload", &b);
+    b.receive(&datagram, &a);
+    b.receive(&datagram, &a);
+
+    assert_eq!(a.rejected_packets(), RejectedPackets::default());
+    assert_eq!(b.rejected_packets().replayed, 1);
+}
@@ -0,0 +1,60 @@
+//! The lobby server that players meet through by room code. See [`counter_attack::lobby`].
+
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use counter_attack::lobby::{self, Lobby, LobbyReply, LobbyRequest};
+
+#[derive(Parser, Debug)]
+#[command(about = "Introduces counter-attack players who join the same room")]
+struct Args {
+    /// UDP port to listen on
+    #[arg(long, default_value_t = 7100)]
+    port: u16,
+}
+
+fn main() {
+    let args = Args::parse();
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port)).unwrap_or_else(|err| {
+        eprintln!("error: could not listen on UDP port {}: {err}", args.port);
+        std::process::exit(1);
+    });
+    // Wake up now and then to forget abandoned rooms even when nothing arrives.
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    println!("Lobby listening on UDP port {}", args.port);
+
+    let mut lobby = Lobby::default();
+    let mut buffer = [0; 512];
+    loop {
+        lobby.expire(Instant::now());
+        let (number_of_bytes, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            // An earlier reply bounced off a player who has gone.
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+            Err(err) => {
+                eprintln!("Network error while receiving: {err}");
+                continue;
+            }
+        };
+        let Some(request) = lobby::decode::<LobbyRequest>(&buffer[..number_of_bytes]) else {
+            continue;
+        };
+        for (to, reply) in lobby.handle(from, request, Instant::now()) {
+            if let LobbyReply::Matched { peer, handle: 0 } = reply {
+                println!("{to} and {peer} met");
+            }
+            if let Err(err) = socket.send_to(&lobby::encode(&reply), to) {
+                eprintln!("Network error while sending to {to}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! The relay server that players who can't reach each other play through. See
+//! [`counter_attack::relay`].
+
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use counter_attack::relay::Relay;
+
+#[derive(Parser, Debug)]
+#[command(about = "Forwards counter-attack traffic between players who share a session ID")]
+struct Args {
+    /// UDP port to listen on
+    #[arg(long, default_value_t = 7200)]
+    port: u16,
+}
+
+fn main() {
+    let args = Args::parse();
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port)).unwrap_or_else(|err| {
+        eprintln!("error: could not listen on UDP port {}: {err}", args.port);
+        std::process::exit(1);
+    });
+    // Wake up now and then to forget idle sessions even when nothing arrives.
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    println!("Relay listening on UDP port {}", args.port);
+
+    let mut relay = Relay::default();
+    let mut buffer = [0; 4096];
+    loop {
+        relay.expire(Instant::now());
+        let (number_of_bytes, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue
+            }
+            // An earlier datagram bounced off a player who has gone.
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+            Err(err) => {
+                eprintln!("Network error while receiving: {err}");
+                continue;
+            }
+        };
+        let datagram = &buffer[..number_of_bytes];
+        if let Some(to) = relay.route(from, datagram, Instant::now()) {
+            if let Err(err) = socket.send_to(datagram, to) {
+                eprintln!("Network error while sending to {to}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Command line options.
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use counter_attack::{
+    lobby::MAX_ROOM_LENGTH,
+    sim::{self, bot::Difficulty, FrameRate, DEFAULT_FPS},
+};
+
+use crate::{
+    auth::SessionKey,
+    conditioner::Conditions,
+    session::{InputDelay, NetSettings, Timeouts, MAX_INPUT_DELAY, SYNCTEST_MAX_PREDICTION},
+};
+
+/// Beyond this a frame would be shorter than a render frame on any display.
+const MAX_FPS: usize = 240;
+
+#[derive(Parser, Debug)]
+#[command(about = "A duel decided by the timing of counter-attacks")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub mode: Mode,
+    /// Write the match's confirmed inputs to this replay file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+    /// Frames simulated per second, which has to match the other player's. Replays play
+    /// back at the rate they were recorded at
+    #[arg(long, global = true, default_value_t = DEFAULT_FPS, value_parser = parse_fps)]
+    pub fps: usize,
+}
+
+impl Cli {
+    pub fn frame_rate(&self) -> FrameRate {
+        FrameRate::from_fps(self.fps).expect("the frame rate is checked when parsed")
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Mode {
+    /// Host a match against a player who joins from REMOTE_ADDR
+    Host {
+        /// The joining player's address, for example 192.168.1.20:7001, or the relay
+        /// server's with --relay-session
+        #[arg(value_parser = parse_addr)]
+        remote_addr: SocketAddr,
+        /// UDP port to listen on
+        #[arg(long, default_value_t = 7000)]
+        port: u16,
+        /// Also send the match to a spectator at this address; can be repeated
+        #[arg(long = "spectator", value_name = "ADDR", value_parser = parse_addr)]
+        spectators: Vec<SocketAddr>,
+        /// Play through the relay server at REMOTE_ADDR, in the session with this ID, which
+        /// the other player has to use too
+        #[arg(long, value_name = "ID")]
+        relay_session: Option<u64>,
+        #[command(flatten)]
+        net: NetOptions,
+    },
+    /// Join a match hosted at HOST_ADDR
+    Join {
+        /// The host's address, for example 192.168.1.10:7000, or the relay server's with
+        /// --relay-session
+        #[arg(value_parser = parse_addr)]
+        host_addr: SocketAddr,
+        /// UDP port to listen on
+        #[arg(long, default_value_t = 7001)]
+        port: u16,
+        /// Play through the relay server at HOST_ADDR, in the session with this ID, which
+        /// the host has to use too
+        #[arg(long, value_name = "ID")]
+        relay_session: Option<u64>,
+        #[command(flatten)]
+        net: NetOptions,
+    },
+    /// Meet the other player through a lobby server, by a room code you both use
+    Lobby {
+        /// The lobby server's address, for example 192.168.1.5:7100
+        #[arg(value_parser = parse_addr)]
+        lobby_addr: SocketAddr,
+        #[arg(value_parser = parse_room)]
+        room: String,
+        /// UDP port to listen on
+        #[arg(long, default_value_t = 7000)]
+        port: u16,
+        #[command(flatten)]
+        net: NetOptions,
+    },
+    /// Play against someone else on this machine: A, or L and any gamepad's south button
+    Local {
+        /// Play against the computer instead: easy, normal, hard or inhuman
+        #[arg(long, value_name = "DIFFICULTY")]
+        bot: Option<Difficulty>,
+    },
+    /// Watch a recorded match
+    Replay { path: PathBuf },
+    /// Play the computer while rolling back and resimulating every frame, panicking as soon
+    /// as the resimulated checksums differ
+    Synctest {
+        /// How many frames to roll back each frame
+        #[arg(long, default_value_t = 2, value_parser = parse_check_distance)]
+        check_distance: usize,
+        /// The computer's difficulty: easy, normal, hard or inhuman
+        #[arg(long, default_value_t = Difficulty::Normal, value_name = "DIFFICULTY")]
+        bot: Difficulty,
+        /// Seed for the computer's timing, to make a run repeatable
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
+    },
+    /// Watch a match hosted at HOST_ADDR, once the host lists this machine as a spectator
+    Spectate {
+        /// The host's address, for example 192.168.1.10:7000
+        #[arg(value_parser = parse_addr)]
+        host_addr: SocketAddr,
+        /// UDP port to listen on
+        #[arg(long, default_value_t = 7002)]
+        port: u16,
+        #[command(flatten)]
+        timeouts: TimeoutOptions,
+        #[command(flatten)]
+        auth: AuthOptions,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct NetOptions {
+    /// Frames to hold local input back for, trading responsiveness for fewer rollbacks, or
+    /// auto to choose from the round trip to the other player
+    #[arg(long, default_value = "0", value_name = "FRAMES", value_parser = parse_input_delay)]
+    pub input_delay: InputDelay,
+    /// Frames to run ahead of the other player's last input before waiting for them
+    #[arg(long, default_value_t = 8, value_name = "FRAMES",
+          value_parser = clap::value_parser!(u64).range(1..=32))]
+    pub max_prediction: u64,
+    /// Compare checksums with the other player every this many confirmed frames, to find
+    /// desyncs. Both players need the same interval for every one to be compared
+    #[arg(long, default_value_t = 10, value_name = "FRAMES",
+          value_parser = clap::value_parser!(u64).range(1..=60))]
+    pub checksum_interval: u64,
+    #[command(flatten)]
+    pub timeouts: TimeoutOptions,
+    #[command(flatten)]
+    pub auth: AuthOptions,
+    #[command(flatten)]
+    pub conditions: ConditionOptions,
+}
+
+impl NetOptions {
+    pub fn settings(&self, frame_rate: FrameRate) -> NetSettings {
+        NetSettings {
+            frame_rate,
+            input_delay: self.input_delay,
+            max_prediction: self.max_prediction as usize,
+            checksum_interval: self.checksum_interval as usize,
+            timeouts: self.timeouts.timeouts(),
+            key: self.auth.key(),
+            conditions: self.conditions.conditions(),
+        }
+    }
+}
+
+/// Options for making the connection worse on purpose, to reproduce bad-connection bugs on
+/// one machine. They apply to what this side receives.
+#[derive(Args, Debug)]
+pub struct ConditionOptions {
+    /// Milliseconds to delay every incoming packet by
+    #[arg(long, default_value_t = 0, value_name = "MS")]
+    pub simulate_latency: u64,
+    /// Up to this many more milliseconds to delay each incoming packet by at random
+    #[arg(long, default_value_t = 0, value_name = "MS")]
+    pub simulate_jitter: u64,
+    /// Percentage of incoming packets to drop
+    #[arg(long, default_value_t = 0.0, value_name = "PERCENT", value_parser = parse_percentage)]
+    pub simulate_loss: f64,
+    /// Percentage of incoming packets to receive twice
+    #[arg(long, default_value_t = 0.0, value_name = "PERCENT", value_parser = parse_percentage)]
+    pub simulate_duplication: f64,
+    /// Percentage of incoming packets to hold back behind later ones
+    #[arg(long, default_value_t = 0.0, value_name = "PERCENT", value_parser = parse_percentage)]
+    pub simulate_reordering: f64,
+    /// Seed for which packets the options above affect, to make a run repeatable
+    #[arg(long, value_name = "SEED")]
+    pub simulate_seed: Option<u64>,
+}
+
+impl ConditionOptions {
+    pub fn conditions(&self) -> Option<Conditions> {
+        let conditions = Conditions {
+            latency: Duration::from_millis(self.simulate_latency),
+            jitter: Duration::from_millis(self.simulate_jitter),
+            loss: self.simulate_loss / 100.0,
+            duplication: self.simulate_duplication / 100.0,
+            reordering: self.simulate_reordering / 100.0,
+            seed: self.simulate_seed,
+        };
+        (!conditions.is_perfect()).then_some(conditions)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct AuthOptions {
+    /// Sign every packet with this key, 64 hex digits the other side uses too, and drop
+    /// any that aren't signed with it or arrive twice
+    #[arg(long, value_name = "HEX", conflicts_with = "passphrase")]
+    pub session_key: Option<SessionKey>,
+    /// Like --session-key, with the key derived from a passphrase the other side uses too.
+    /// Anyone who captures a packet can try guessing it offline, so make it long and random
+    #[arg(long)]
+    pub passphrase: Option<String>,
+}
+
+impl AuthOptions {
+    pub fn key(&self) -> Option<SessionKey> {
+        self.session_key
+            .or_else(|| self.passphrase.as_deref().map(SessionKey::from_passphrase))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TimeoutOptions {
+    /// Milliseconds without a packet before giving up on the other side
+    #[arg(long, default_value_t = 2000, value_name = "MS")]
+    pub disconnect_timeout: u64,
+    /// Milliseconds without a packet before showing the connection as interrupted
+    #[arg(long, default_value_t = 500, value_name = "MS")]
+    pub disconnect_notify_start: u64,
+}
+
+impl TimeoutOptions {
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            disconnect_notify_start: Duration::from_millis(self.disconnect_notify_start),
+            disconnect_timeout: Duration::from_millis(self.disconnect_timeout),
+        }
+    }
+}
+
+fn parse_room(room: &str) -> Result<String, String> {
+    if room.is_empty() || room.len() > MAX_ROOM_LENGTH {
+        return Err(format!("room codes are 1 to {MAX_ROOM_LENGTH} bytes long"));
+    }
+    Ok(room.into())
+}
+
+fn parse_input_delay(delay: &str) -> Result<InputDelay, String> {
+    if delay == "auto" {
+        return Ok(InputDelay::Auto);
+    }
+    match delay.parse() {
+        Ok(frames) if frames <= MAX_INPUT_DELAY => Ok(InputDelay::Frames(frames)),
+        _ => Err(format!(
+            "expected auto or a number of frames from 0 to {MAX_INPUT_DELAY}"
+        )),
+    }
+}
+
+fn parse_percentage(percentage: &str) -> Result<f64, String> {
+    match percentage.parse() {
+        Ok(percentage) if (0.0..=100.0).contains(&percentage) => Ok(percentage),
+        _ => Err("expected a percentage from 0 to 100".into()),
+    }
+}
+
+/// Below 2 frames a sync test has no checksums to compare, and it can't roll back as far
+/// as it predicts.
+fn parse_check_distance(distance: &str) -> Result<usize, String> {
+    match distance.parse() {
+        Ok(frames) if (2..SYNCTEST_MAX_PREDICTION).contains(&frames) => Ok(frames),
+        _ => Err(format!(
+            "expected a number of frames from 2 to {}",
+            SYNCTEST_MAX_PREDICTION - 1
+        )),
+    }
+}
+
+fn parse_fps(fps: &str) -> Result<usize, String> {
+    let fps: usize = fps.parse().map_err(|err| format!("{err}"))?;
+    if !(1..=MAX_FPS).contains(&fps) || !sim::supports_fps(fps) {
+        return Err(format!(
+            "{fps} frames per second is not supported; try 30, 60, 120 or 144"
+        ));
+    }
+    Ok(fps)
+}
+
+/// Accepts host names as well as IP addresses, and rejects addresses that can't be sent to.
+fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    let resolved = addr
+        .to_socket_addrs()
+        .map_err(|err| format!("{err}; expected an address like 192.168.1.20:7000"))?
+        .next()
+        .ok_or_else(|| format!("{addr} did not resolve to any address"))?;
+    if resolved.ip().is_unspecified() || resolved.port() == 0 {
+        return Err(format!(
+            "{resolved} can't be sent to; use the address and port the other machine listens on"
+        ));
+    }
+    Ok(resolved)
+}
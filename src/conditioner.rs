@@ -0,0 +1,133 @@
+//! Making a good connection behave like a bad one, to reproduce on one machine what
+//! players see over the internet.
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use counter_attack::rng::SplitMix64;
+
+use crate::socket::{Transport, RECV_BUFFER_SIZE};
+
+/// How much longer than the rest a reordered datagram takes, enough to arrive after the
+/// next frame's.
+const REORDER_DELAY: Duration = Duration::from_millis(30);
+
+/// What happens to each datagram on its way in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditions {
+    /// Added to every datagram's travel time.
+    pub latency: Duration,
+    /// Up to this much more is added at random, which also reorders datagrams close
+    /// together.
+    pub jitter: Duration,
+    /// The chance of dropping a datagram, from 0 to 1.
+    pub loss: f64,
+    /// The chance of a datagram arriving twice.
+    pub duplication: f64,
+    /// The chance of a datagram being held back behind later ones.
+    pub reordering: f64,
+    /// Makes the same datagrams meet the same fate on every run, given the same timing.
+    /// Random if not given.
+    pub seed: Option<u64>,
+}
+
+impl Conditions {
+    /// Whether these leave datagrams alone.
+    pub fn is_perfect(&self) -> bool {
+        self.latency.is_zero()
+            && self.jitter.is_zero()
+            && self.loss == 0.0
+            && self.duplication == 0.0
+            && self.reordering == 0.0
+    }
+}
+
+struct Delayed {
+    due: Instant,
+    src_addr: SocketAddr,
+    datagram: Vec<u8>,
+}
+
+/// Applies [`Conditions`] to everything received through `inner`. Condition both sides to
+/// affect both directions.
+pub struct ConditionedTransport<T> {
+    inner: T,
+    conditions: Conditions,
+    rng: SplitMix64,
+    /// In the order they're due.
+    queue: VecDeque<Delayed>,
+    buffer: Vec<u8>,
+}
+
+impl<T: Transport> ConditionedTransport<T> {
+    pub fn new(inner: T, conditions: Conditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            rng: SplitMix64::new(
+                conditions
+                    .seed
+                    .unwrap_or_else(|| RandomState::new().build_hasher().finish()),
+            ),
+            queue: VecDeque::new(),
+            buffer: vec![0; RECV_BUFFER_SIZE],
+        }
+    }
+
+    fn delay(&mut self, src_addr: SocketAddr, datagram: Vec<u8>, now: Instant) {
+        let conditions = self.conditions;
+        let mut due = now + conditions.latency + conditions.jitter.mul_f64(self.rng.next_f64());
+        if self.rng.chance(conditions.reordering) {
+            due += REORDER_DELAY;
+        }
+        // Datagrams due at the same time keep the order they came in.
+        let index = self.queue.partition_point(|delayed| delayed.due <= due);
+        self.queue.insert(
+            index,
+            Delayed {
+                due,
+                src_addr,
+                datagram,
+            },
+        );
+    }
+}
+
+impl<T: Transport> Transport for ConditionedTransport<T> {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.inner.send_to(datagram, addr)
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let now = Instant::now();
+        loop {
+            match self.inner.recv_from(&mut self.buffer) {
+                Ok((number_of_bytes, src_addr)) => {
+                    if self.rng.chance(self.conditions.loss) {
+                        continue;
+                    }
+                    let datagram = self.buffer[..number_of_bytes].to_vec();
+                    if self.rng.chance(self.conditions.duplication) {
+                        self.delay(src_addr, datagram.clone(), now);
+                    }
+                    self.delay(src_addr, datagram, now);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        match self.queue.front() {
+            Some(delayed) if delayed.due <= now => {
+                let delayed = self.queue.pop_front().unwrap();
+                let number_of_bytes = delayed.datagram.len().min(buffer.len());
+                buffer[..number_of_bytes].copy_from_slice(&delayed.datagram[..number_of_bytes]);
+                Ok((number_of_bytes, delayed.src_addr))
+            }
+            _ => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
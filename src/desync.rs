@@ -0,0 +1,223 @@
+//! Finding frames that the peers disagree about, and dumping the local snapshot of them.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    fs, io, mem,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+use ggrs::{Frame, GGRSEvent};
+
+use crate::{
+    session::GGRSConfig,
+    sim::{format, WorldSnapshot},
+    socket::{ChecksumMessage, Checksums},
+};
+
+/// How many saved frames to keep around. Desyncs are only reported once the remote
+/// checksum for a frame arrives, which can be a while after it was saved.
+const HISTORY_FRAMES: usize = 600;
+
+/// A checksum the other player hasn't acknowledged yet.
+struct Unacknowledged {
+    checksum: u128,
+    /// The last confirmed frame when it was last sent.
+    last_sent: Option<Frame>,
+}
+
+/// Compares the checksums of confirmed frames with the other player's, every `interval`
+/// frames.
+///
+/// GGRS's own desync detection checksums the frame before the last one saved, which may
+/// still be predicted, so it reports a desync after any rollback that changes that frame.
+/// Each checksum is sent again every `interval` frames until the other player acknowledges
+/// it, so that a lost datagram doesn't leave a frame unchecked.
+pub struct DesyncDetector {
+    checksums: Arc<Mutex<Checksums>>,
+    remote_addr: SocketAddr,
+    interval: Frame,
+    /// Checksums of the frames saved since the last confirmed one.
+    unconfirmed: BTreeMap<Frame, u128>,
+    /// Checksums sent to the other player that they haven't acknowledged.
+    unacknowledged: BTreeMap<Frame, Unacknowledged>,
+    /// Checksums of confirmed frames, waiting for the other player's.
+    local: BTreeMap<Frame, u128>,
+    /// The other player's checksums, waiting for this player to confirm the same frame.
+    remote: BTreeMap<Frame, u128>,
+    last_confirmed: Frame,
+    events: Vec<GGRSEvent<GGRSConfig>>,
+}
+
+impl DesyncDetector {
+    pub fn new(checksums: Arc<Mutex<Checksums>>, remote_addr: SocketAddr, interval: usize) -> Self {
+        Self {
+            checksums,
+            remote_addr,
+            interval: interval as Frame,
+            unconfirmed: BTreeMap::new(),
+            unacknowledged: BTreeMap::new(),
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            last_confirmed: -1,
+            events: vec![],
+        }
+    }
+    /// Keeps the checksum of a snapshot just saved, forgetting those of the frames after
+    /// it, which a rollback has undone.
+    pub fn save(&mut self, frame: Frame, checksum: u128) {
+        if frame <= self.last_confirmed {
+            return;
+        }
+        self.unconfirmed.split_off(&frame);
+        self.unconfirmed.insert(frame, checksum);
+    }
+    /// Sends the checksums of the frames saved up to `confirmed_frame`, along with any
+    /// still unacknowledged, and compares those that both players have sent. A frame is
+    /// compared at most once, so it is reported at most once.
+    pub fn confirm(&mut self, confirmed_frame: Frame) {
+        // GGRS can confirm frames before they have been simulated here.
+        let last_saved = self.unconfirmed.keys().next_back().copied();
+        let confirmed_frame = confirmed_frame.min(last_saved.unwrap_or(self.last_confirmed));
+        if confirmed_frame > self.last_confirmed {
+            let still_unconfirmed = self.unconfirmed.split_off(&(confirmed_frame + 1));
+            for (frame, checksum) in mem::replace(&mut self.unconfirmed, still_unconfirmed) {
+                if frame % self.interval == 0 {
+                    self.local.insert(frame, checksum);
+                    self.unacknowledged.insert(
+                        frame,
+                        Unacknowledged {
+                            checksum,
+                            last_sent: None,
+                        },
+                    );
+                }
+            }
+            self.last_confirmed = confirmed_frame;
+        }
+        // Neither side keeps checksums for long, and they can run ahead of each other by
+        // no more than that.
+        let history = HISTORY_FRAMES as Frame;
+        let kept = self.last_confirmed - history..=self.last_confirmed + history;
+
+        let mut checksums = self.checksums.lock().unwrap();
+        for (addr, message) in mem::take(&mut checksums.incoming) {
+            if addr != self.remote_addr {
+                continue;
+            }
+            match message {
+                ChecksumMessage::Checksum { frame, checksum } => {
+                    // Acknowledged every time, in case the last acknowledgement was lost.
+                    let ack = ChecksumMessage::Ack { frame };
+                    checksums.outgoing.push((self.remote_addr, ack));
+                    if kept.contains(&frame) {
+                        self.remote.insert(frame, checksum);
+                    }
+                }
+                ChecksumMessage::Ack { frame } => {
+                    self.unacknowledged.remove(&frame);
+                }
+            }
+        }
+        self.unacknowledged.retain(|frame, _| kept.contains(frame));
+        for (&frame, unacknowledged) in &mut self.unacknowledged {
+            if unacknowledged
+                .last_sent
+                .is_none_or(|last_sent| self.last_confirmed - last_sent >= self.interval)
+            {
+                let checksum = unacknowledged.checksum;
+                let message = ChecksumMessage::Checksum { frame, checksum };
+                checksums.outgoing.push((self.remote_addr, message));
+                unacknowledged.last_sent = Some(self.last_confirmed);
+            }
+        }
+        drop(checksums);
+
+        let compared: Vec<Frame> = self
+            .remote
+            .keys()
+            .filter(|frame| self.local.contains_key(frame))
+            .copied()
+            .collect();
+        for frame in compared {
+            let local_checksum = self.local.remove(&frame).unwrap();
+            let remote_checksum = self.remote.remove(&frame).unwrap();
+            if local_checksum != remote_checksum {
+                self.events.push(GGRSEvent::DesyncDetected {
+                    frame,
+                    local_checksum,
+                    remote_checksum,
+                    addr: self.remote_addr,
+                });
+            }
+        }
+        // A checksum resent after it was compared arrives again, and one from a player who
+        // has gone quiet never does.
+        self.local.retain(|frame, _| kept.contains(frame));
+        self.remote.retain(|frame, _| kept.contains(frame));
+    }
+    /// The desyncs found since this was last called.
+    pub fn events(&mut self) -> Vec<GGRSEvent<GGRSConfig>> {
+        mem::take(&mut self.events)
+    }
+}
+
+/// The most recently saved snapshots, oldest first.
+#[derive(Resource, Default)]
+pub struct SnapshotHistory(VecDeque<WorldSnapshot>);
+
+impl SnapshotHistory {
+    pub fn save(&mut self, world_snapshot: WorldSnapshot) {
+        // Anything at or after this frame was from a timeline that has been rolled back.
+        while self
+            .0
+            .back()
+            .is_some_and(|saved| saved.frame >= world_snapshot.frame)
+        {
+            self.0.pop_back();
+        }
+        if self.0.len() == HISTORY_FRAMES {
+            self.0.pop_front();
+        }
+        self.0.push_back(world_snapshot);
+    }
+    pub fn get(&self, frame: Frame) -> Option<&WorldSnapshot> {
+        self.0.iter().find(|saved| saved.frame as Frame == frame)
+    }
+}
+
+/// Writes the local snapshot for a desynced `frame` to `dir`, named after the local
+/// checksum so that two peers on one machine don't overwrite each other.
+///
+/// The snapshot is written both as readable RON, with the checksums in a comment header,
+/// and in the binary format next to it.
+pub fn dump_desync(
+    history: &SnapshotHistory,
+    dir: &Path,
+    frame: Frame,
+    local_checksum: u128,
+    remote_checksum: u128,
+    addr: SocketAddr,
+) -> io::Result<PathBuf> {
+    let Some(world_snapshot) = history.get(frame) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("frame {frame} is no longer in the snapshot history"),
+        ));
+    };
+
+    let mut dump = String::new();
+    writeln!(dump, "// frame: {frame}").unwrap();
+    writeln!(dump, "// local checksum: {local_checksum:032x}").unwrap();
+    writeln!(dump, "// remote checksum: {remote_checksum:032x}").unwrap();
+    writeln!(dump, "// remote address: {addr}").unwrap();
+    dump.push_str(&format::to_readable(world_snapshot));
+
+    let path = dir.join(format!("desync-{frame}-{local_checksum:032x}.ron"));
+    fs::write(&path, dump)?;
+    fs::write(path.with_extension("bin"), format::to_binary(world_snapshot))?;
+    Ok(path)
+}
@@ -0,0 +1,79 @@
+//! Presentation effects of the simulation, kept consistent across rollbacks.
+//!
+//! Every [`SimEvent`] is stamped with the frame it happened on. Resimulating a frame that
+//! produces the same events again starts nothing new, and events that a rollback shows
+//! never happened are cancelled.
+
+use std::{collections::BTreeMap, mem};
+
+use bevy::prelude::*;
+
+use crate::sim::SimEvent;
+
+/// A [`SimEvent`] and the frame it happened on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Effect {
+    pub frame: usize,
+    pub event: SimEvent,
+}
+
+pub enum EffectEvent {
+    Started(Effect),
+    /// A rollback showed that a started effect never happened.
+    Cancelled(Effect),
+}
+
+/// Remembers the effects started for every frame that is not confirmed yet.
+#[derive(Resource, Default)]
+pub struct EffectLedger {
+    frames: BTreeMap<usize, Vec<SimEvent>>,
+    confirmed_frame: Option<usize>,
+}
+
+impl EffectLedger {
+    /// Records the events produced by (re)simulating `frame`, returning what needs to be
+    /// cancelled and started to make the presentation match.
+    pub fn record(&mut self, frame: usize, events: Vec<SimEvent>) -> Vec<EffectEvent> {
+        let mut stale = self.frames.remove(&frame).unwrap_or_default();
+        let mut started = vec![];
+        for event in &events {
+            if let Some(index) = stale.iter().position(|played| played == event) {
+                stale.swap_remove(index);
+            } else {
+                started.push(EffectEvent::Started(Effect {
+                    frame,
+                    event: event.clone(),
+                }));
+            }
+        }
+        if !events.is_empty() {
+            self.frames.insert(frame, events);
+        }
+
+        stale
+            .into_iter()
+            .map(|event| EffectEvent::Cancelled(Effect { frame, event }))
+            .chain(started)
+            .collect()
+    }
+    /// Forgets every frame up to and including `frame`, which can no longer be rolled back,
+    /// returning the effects that are now certain to have happened.
+    pub fn confirm(&mut self, frame: usize) -> Vec<Effect> {
+        let unconfirmed = self.frames.split_off(&(frame + 1));
+        let confirmed = mem::replace(&mut self.frames, unconfirmed);
+        self.confirmed_frame = Some(frame);
+        confirmed
+            .into_iter()
+            .flat_map(|(frame, events)| {
+                events.into_iter().map(move |event| Effect { frame, event })
+            })
+            .collect()
+    }
+    pub fn is_confirmed(&self, frame: usize) -> bool {
+        self.confirmed_frame
+            .is_some_and(|confirmed_frame| frame <= confirmed_frame)
+    }
+}
+
+#[cfg(test)]
+mod tests;
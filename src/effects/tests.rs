@@ -0,0 +1,71 @@
+//! The ledger, fed the events of frames simulated, rolled back and confirmed.
+
+use std::slice;
+
+use counter_attack::sim::SimEvent;
+
+use super::*;
+
+const BLOCK: SimEvent = SimEvent::Block {
+    handle: 1,
+    quality: 1.0,
+};
+
+/// The effects cancelled and started by `events`, in that order.
+fn cancelled_and_started(events: Vec<EffectEvent>) -> (Vec<Effect>, Vec<Effect>) {
+    let mut cancelled = vec![];
+    let mut started = vec![];
+    for event in events {
+        match event {
+            EffectEvent::Cancelled(effect) => cancelled.push(effect),
+            EffectEvent::Started(effect) => started.push(effect),
+        }
+    }
+    (cancelled, started)
+}
+
+#[test]
+fn an_effect_recorded_again_after_a_rollback_plays_once() {
+    let mut ledger = EffectLedger::default();
+    let block = Effect {
+        frame: 10,
+        event: BLOCK,
+    };
+
+    let (cancelled, started) = cancelled_and_started(ledger.record(10, vec![BLOCK]));
+    assert_eq!(cancelled, []);
+    assert_eq!(started, slice::from_ref(&block));
+    // Rolled back to before frame 10, which happens the same way again.
+    let (cancelled, started) = cancelled_and_started(ledger.record(10, vec![BLOCK]));
+    assert_eq!(cancelled, []);
+    assert_eq!(started, []);
+
+    assert_eq!(ledger.confirm(10), [block]);
+    assert!(ledger.is_confirmed(10));
+}
+
+#[test]
+fn an_effect_from_an_abandoned_timeline_is_dropped_at_confirmation() {
+    let mut ledger = EffectLedger::default();
+    let block = Effect {
+        frame: 10,
+        event: BLOCK,
+    };
+    let parry = Effect {
+        frame: 11,
+        event: SimEvent::FinalClashParry,
+    };
+
+    let (_, started) = cancelled_and_started(ledger.record(10, vec![BLOCK]));
+    assert_eq!(started, slice::from_ref(&block));
+    // Rolled back to before frame 10, which now happens without the block.
+    let (cancelled, started) = cancelled_and_started(ledger.record(10, vec![]));
+    assert_eq!(cancelled, [block]);
+    assert_eq!(started, []);
+    let (cancelled, started) =
+        cancelled_and_started(ledger.record(11, vec![SimEvent::FinalClashParry]));
+    assert_eq!(cancelled, []);
+    assert_eq!(started, slice::from_ref(&parry));
+
+    assert_eq!(ledger.confirm(11), [parry]);
+}
@@ -0,0 +1,24 @@
+//! The parts of counter-attack that don't need a window: the duel rules and their
+//! serialization, and the lobby and relay protocols, shared by the game and any tooling
+//! built around it.
+
+pub mod lobby;
+pub mod relay;
+pub mod rng;
+pub mod sim;
+
+// The game stores simulation state directly in the ECS. The orphan rule only allows these
+// impls in this crate, and keeping them here leaves `sim` itself free of Bevy. Without the
+// `bevy` feature, nothing here needs it.
+#[cfg(feature = "bevy")]
+mod ecs {
+    use bevy::ecs::{component::TableStorage, prelude::*};
+
+    use crate::sim;
+
+    impl Component for sim::Player {
+        type Storage = TableStorage;
+    }
+    impl Resource for sim::FinalClash {}
+    impl Resource for sim::GameState {}
+}
@@ -0,0 +1,136 @@
+//! Matching two players by a room code they both know, so neither needs the other's
+//! address.
+//!
+//! Each player sends [`LobbyRequest::Join`] to the lobby server from the UDP socket their
+//! session will use, so the address the lobby sees is the one the other player can reach.
+//! Once a second player joins a room the lobby sends each the other's address and steps
+//! out of the way; the match itself goes directly between the players.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use ggrs::PlayerHandle;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Starts every lobby datagram, so that a game socket can tell them from GGRS messages.
+pub const TAG: &[u8; 4] = b"LOBY";
+/// Room codes are at most this many bytes.
+pub const MAX_ROOM_LENGTH: usize = 32;
+/// How often a player resends [`LobbyRequest::Join`] until matched.
+pub const JOIN_INTERVAL: Duration = Duration::from_millis(500);
+/// A player who stops resending their join is forgotten after this long.
+const WAITING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a matched room is remembered, to answer joins resent because the reply was
+/// lost.
+const MATCHED_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LobbyRequest {
+    Join { room: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LobbyReply {
+    /// Nobody else is in the room yet.
+    Waiting,
+    /// Connect to `peer`, playing as `handle`. Whoever joined first hosts as handle 0.
+    Matched {
+        peer: SocketAddr,
+        handle: PlayerHandle,
+    },
+    /// Two other players already met in the room.
+    RoomFull,
+}
+
+pub fn encode(message: &impl Serialize) -> Vec<u8> {
+    let mut datagram = TAG.to_vec();
+    bincode::serialize_into(&mut datagram, message).unwrap();
+    datagram
+}
+
+/// The message in `datagram`, unless it isn't a lobby datagram or doesn't parse.
+pub fn decode<T: DeserializeOwned>(datagram: &[u8]) -> Option<T> {
+    let message = datagram.strip_prefix(TAG)?;
+    bincode::deserialize(message).ok()
+}
+
+enum Room {
+    Waiting {
+        host: SocketAddr,
+        last_join: Instant,
+    },
+    Matched {
+        players: [SocketAddr; 2],
+        at: Instant,
+    },
+}
+
+/// The lobby server's rooms, separate from its socket.
+#[derive(Default)]
+pub struct Lobby {
+    rooms: HashMap<String, Room>,
+}
+
+impl Lobby {
+    /// Handles a request from `from`, returning the replies to send and who to send them to.
+    pub fn handle(
+        &mut self,
+        from: SocketAddr,
+        request: LobbyRequest,
+        now: Instant,
+    ) -> Vec<(SocketAddr, LobbyReply)> {
+        let LobbyRequest::Join { room } = request;
+        if room.is_empty() || room.len() > MAX_ROOM_LENGTH {
+            return vec![];
+        }
+        match self.rooms.get_mut(&room) {
+            None => {
+                self.rooms.insert(
+                    room,
+                    Room::Waiting {
+                        host: from,
+                        last_join: now,
+                    },
+                );
+                vec![(from, LobbyReply::Waiting)]
+            }
+            Some(Room::Waiting { host, last_join }) if *host == from => {
+                *last_join = now;
+                vec![(from, LobbyReply::Waiting)]
+            }
+            Some(Room::Waiting { host, .. }) => {
+                let players = [*host, from];
+                self.rooms.insert(room, Room::Matched { players, at: now });
+                vec![
+                    (players[0], matched(players, 0)),
+                    (players[1], matched(players, 1)),
+                ]
+            }
+            Some(Room::Matched { players, .. }) => match players.iter().position(|&p| p == from) {
+                Some(handle) => vec![(from, matched(*players, handle))],
+                None => vec![(from, LobbyReply::RoomFull)],
+            },
+        }
+    }
+
+    /// Forgets players who stopped waiting and rooms matched long enough ago.
+    pub fn expire(&mut self, now: Instant) {
+        self.rooms.retain(|_, room| match room {
+            Room::Waiting { last_join, .. } => now - *last_join < WAITING_TIMEOUT,
+            Room::Matched { at, .. } => now - *at < MATCHED_TIMEOUT,
+        });
+    }
+}
+
+fn matched(players: [SocketAddr; 2], handle: PlayerHandle) -> LobbyReply {
+    LobbyReply::Matched {
+        peer: players[1 - handle],
+        handle,
+    }
+}
+
+#[cfg(test)]
+mod tests;
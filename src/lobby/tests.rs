@@ -0,0 +1,156 @@
+//! The lobby's rooms, driven with requests from made-up addresses at made-up times.
+
+use super::*;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn join(room: &str) -> LobbyRequest {
+    LobbyRequest::Join { room: room.into() }
+}
+
+#[test]
+fn the_second_player_in_a_room_is_matched_with_the_first() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    let [host, guest] = [addr(7000), addr(7001)];
+
+    assert_eq!(
+        lobby.handle(host, join("dojo"), now),
+        [(host, LobbyReply::Waiting)]
+    );
+    assert_eq!(
+        lobby.handle(guest, join("dojo"), now),
+        [
+            (
+                host,
+                LobbyReply::Matched {
+                    peer: guest,
+                    handle: 0
+                }
+            ),
+            (
+                guest,
+                LobbyReply::Matched {
+                    peer: host,
+                    handle: 1
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn players_in_different_rooms_wait_apart() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+
+    lobby.handle(addr(7000), join("dojo"), now);
+    assert_eq!(
+        lobby.handle(addr(7001), join("temple"), now),
+        [(addr(7001), LobbyReply::Waiting)]
+    );
+}
+
+#[test]
+fn a_join_resent_after_matching_gets_the_match_again() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    let [host, guest] = [addr(7000), addr(7001)];
+    lobby.handle(host, join("dojo"), now);
+    lobby.handle(guest, join("dojo"), now);
+
+    let later = now + JOIN_INTERVAL;
+    assert_eq!(
+        lobby.handle(guest, join("dojo"), later),
+        [(
+            guest,
+            LobbyReply::Matched {
+                peer: host,
+                handle: 1
+            }
+        )]
+    );
+    assert_eq!(
+        lobby.handle(host, join("dojo"), later),
+        [(
+            host,
+            LobbyReply::Matched {
+                peer: guest,
+                handle: 0
+            }
+        )]
+    );
+}
+
+#[test]
+fn a_third_player_finds_the_room_full() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    lobby.handle(addr(7000), join("dojo"), now);
+    lobby.handle(addr(7001), join("dojo"), now);
+
+    assert_eq!(
+        lobby.handle(addr(7002), join("dojo"), now),
+        [(addr(7002), LobbyReply::RoomFull)]
+    );
+}
+
+#[test]
+fn room_codes_that_are_empty_or_too_long_are_ignored() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+
+    assert_eq!(lobby.handle(addr(7000), join(""), now), []);
+    let too_long = "x".repeat(MAX_ROOM_LENGTH + 1);
+    assert_eq!(lobby.handle(addr(7000), join(&too_long), now), []);
+}
+
+#[test]
+fn a_player_who_stops_resending_their_join_is_forgotten() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    lobby.handle(addr(7000), join("dojo"), now);
+
+    // Still there as long as they keep joining.
+    lobby.handle(addr(7000), join("dojo"), now + WAITING_TIMEOUT / 2);
+    lobby.expire(now + WAITING_TIMEOUT);
+    lobby.expire(now + WAITING_TIMEOUT / 2 + WAITING_TIMEOUT);
+
+    assert_eq!(
+        lobby.handle(addr(7001), join("dojo"), now + 2 * WAITING_TIMEOUT),
+        [(addr(7001), LobbyReply::Waiting)]
+    );
+}
+
+#[test]
+fn a_matched_room_is_forgotten_after_a_while() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    lobby.handle(addr(7000), join("dojo"), now);
+    lobby.handle(addr(7001), join("dojo"), now);
+
+    lobby.expire(now + MATCHED_TIMEOUT / 2);
+    assert_eq!(
+        lobby.handle(addr(7002), join("dojo"), now + MATCHED_TIMEOUT / 2),
+        [(addr(7002), LobbyReply::RoomFull)]
+    );
+    lobby.expire(now + MATCHED_TIMEOUT);
+    assert_eq!(
+        lobby.handle(addr(7002), join("dojo"), now + MATCHED_TIMEOUT),
+        [(addr(7002), LobbyReply::Waiting)]
+    );
+}
+
+#[test]
+fn messages_survive_encoding() {
+    let request = join("dojo");
+    assert_eq!(decode::<LobbyRequest>(&encode(&request)), Some(request));
+    let reply = LobbyReply::Matched {
+        peer: addr(7000),
+        handle: 1,
+    };
+    assert_eq!(decode::<LobbyReply>(&encode(&reply)), Some(reply));
+    assert_eq!(decode::<LobbyReply>(b"GGRS message"), None);
+}
@@ -1,17 +1,26 @@
+mod auth;
+mod cli;
+mod conditioner;
+mod desync;
+mod effects;
+mod recording;
+mod session;
+mod socket;
 mod ui;
 
 use std::{
-    cmp::Ordering,
-    collections::{hash_map::DefaultHasher},
-    hash::{Hash, Hasher},
-    net::SocketAddr,
-    ops::{Add, AddAssign, Neg, Sub, SubAssign},
-    thread::sleep,
-    time::{Duration, Instant}, iter::repeat,
+    collections::VecDeque,
+    iter::repeat_n,
+    path::Path,
+    process::exit,
+    time::{Duration, Instant, SystemTime},
 };
 
+use auth::RejectedPackets;
 use bevy::{
+    app::AppExit,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    ecs::system::SystemParam,
     prelude::*,
     reflect::TypeUuid,
     render::render_resource::{FilterMode, SamplerDescriptor},
@@ -21,254 +30,168 @@ use bevy_asset_loader::prelude::{AssetCollection, LoadingState, LoadingStateAppE
 use bevy_easings::{EaseValue, Lerp};
 use bevy_hanabi::prelude::*;
 use bevy_sprite3d::{AtlasSprite3d, AtlasSprite3dComponent, Sprite3dParams, Sprite3dPlugin};
-use bytemuck::{Pod, Zeroable};
 use clap::Parser;
+use cli::{Cli, Mode};
+use counter_attack::sim::{
+    self, bot::Bot, replay::Replay, simulate, FinalClash, FrameOffset, FrameRate, GameState,
+    Player, Second, SendInput, SimEvent, WorldSnapshot, TEST_ATTACK,
+};
+use desync::{dump_desync, SnapshotHistory};
+use effects::{Effect, EffectEvent, EffectLedger};
 use ggrs::{
-    Config, GGRSRequest, InputStatus, P2PSession, PlayerHandle, PlayerType, SessionBuilder,
-    UdpNonBlockingSocket,
+    GGRSError, GGRSEvent, GGRSRequest, InputStatus, NetworkStats, PlayerHandle, SessionState,
 };
-use iunorm::{Inorm64, Unorm64};
+use recording::{load_replay, ReplayRecorder};
+use session::{P2PSettings, Session, SessionEvent};
 use ui::{Roboto, GUI};
 //https://freesound.org/people/aarrnnoo/sounds/516189/
 
-const FRAMETIME: f64 = 0.1;
-const FPS: f64 = 1.0 / FRAMETIME;
+/// The sprite animations are drawn at this many frames per second, whatever the tick rate.
+const ANIMATION_FPS: f64 = 10.0;
+/// How much longer ticks take while this peer is ahead of the other, letting it fall back
+/// in sync over a few frames.
+const AHEAD_SLOWDOWN: f64 = 1.1;
 const VOLUME_SCALE: f32 = 0.5;
-const BASE_STAMINA_LOSS: f64 = 0.1;
-const CLASH_LENGTH: Second = Second(1.0);
-const FINAL_CLASH_LIVES: u8 = 4;
-
-const TEST_ATTACK: Attack = Attack {
-    startup_time: Second(0.9),
-    block_grace: Second(0.3),
-    recover_time: Second(0.2),
-};
-
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
-struct Second(f64);
-
-impl Hash for Second {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.to_be_bytes().hash(state);
-    }
-}
-impl Neg for Second {
-    type Output = Self;
 
-    fn neg(self) -> Self::Output {
-        Self(-self.0)
-    }
-}
-
-#[derive(Parser, Debug)]
-struct Args {
-    local_port: u16,
-    remote_addr: SocketAddr,
-}
-
-#[derive(Clone, Debug, Hash)]
-struct Attack {
-    startup_time: Second,
-    block_grace: Second,
-    recover_time: Second,
-}
 #[derive(Resource, Debug)]
 struct LastTickTime {
     // frame_offset: FrameOffset,
     frame: usize,
     instant: Instant,
+    frame_rate: FrameRate,
 }
-#[repr(C)]
-#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable, Hash, Eq)]
-struct FrameOffset {
-    frame: usize,
-    offset: u64,
-}
-impl PartialOrd for FrameOffset {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(if self.frame > other.frame {
-            Ordering::Greater
-        } else if self.frame < other.frame {
-            Ordering::Less
-        } else {
-            if self.offset > other.offset {
-                Ordering::Greater
-            } else if self.offset > other.offset {
-                Ordering::Less
-            } else {
-                Ordering::Equal
-            }
-        })
-    }
-}
-impl Ord for FrameOffset {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
-    }
-}
-impl Add<Second> for FrameOffset {
-    type Output = FrameOffset;
-
-    fn add(mut self, rhs: Second) -> Self::Output {
-        let mut out_offset = Unorm64(self.offset).to_f64() + rhs.0 / FRAMETIME;
-        loop {
-            if out_offset > 1.0 {
-                self.frame += 1;
-                out_offset -= 1.0;
-            } else if out_offset < 0.0 {
-                self.frame -= 1;
-                out_offset += 1.0;
-            } else {
-                break;
-            }
-        }
-        Self {
-            frame: self.frame,
-            offset: Unorm64::from_f64(out_offset).0,
-        }
-    }
-}
-
-impl Add<FrameOffset> for Second {
-    type Output = FrameOffset;
 
-    fn add(self, rhs: FrameOffset) -> Self::Output {
-        rhs.add(self)
+impl LastTickTime {
+    /// The current point in simulation time, interpolated from the wall clock.
+    fn now(&self) -> FrameOffset {
+        let elapsed = Second::from_secs_f64(self.instant.elapsed().as_secs_f64().min(1.0));
+        FrameOffset::at_frame(self.frame).after(elapsed, self.frame_rate)
     }
 }
-impl Sub<FrameOffset> for Second {
-    type Output = FrameOffset;
 
-    fn sub(self, rhs: FrameOffset) -> Self::Output {
-        rhs.add(-self)
-    }
-}
+// #[derive(Component, Debug, Clone)]
+// struct AnimatedAtlas {
+//     atlas: TextureAtlas,
+// }
 
-impl Sub<Second> for FrameOffset {
-    type Output = Self;
+/// Input gathered since the last frame, indexed by player handle.
+#[derive(Debug, Resource, Default)]
+struct LocalInput {
+    attacking: [Option<FrameOffset>; 2],
+    // defending: Option<Instant>,
+    /// The last swing each handle sent, which input delay may not have let through to
+    /// the simulation yet.
+    last_swing: [Option<FrameOffset>; 2],
+    /// The last input each handle sent, which the next one's swing count follows on from.
+    last_input: [SendInput; 2],
+}
 
-    fn sub(self, rhs: Second) -> Self::Output {
-        self.add(-rhs)
+impl LocalInput {
+    /// The input for `input_delay` frames after the frame after `world`, swinging if a
+    /// swing was pressed since the last frame.
+    fn take(
+        &mut self,
+        handle: PlayerHandle,
+        world: &WorldSnapshot,
+        input_delay: usize,
+    ) -> SendInput {
+        if let Some(swing) = self.take_swing(handle, world, input_delay) {
+            self.last_input[handle] = self.last_input[handle].swung(swing);
+        }
+        self.last_input[handle]
     }
-}
-impl SubAssign<Second> for FrameOffset {
-    fn sub_assign(&mut self, rhs: Second) {
-        *self = *self - rhs
+    /// The swing pressed since the last frame, held back by `input_delay` frames after
+    /// `world`'s, or none if the simulation wouldn't allow it by then.
+    fn take_swing(
+        &mut self,
+        handle: PlayerHandle,
+        world: &WorldSnapshot,
+        input_delay: usize,
+    ) -> Option<FrameOffset> {
+        let pressed = self.attacking[handle].take()?;
+        // A late tick can leave the press past the end of the frame being captured.
+        let frame_start = FrameOffset::at_frame(world.frame);
+        let frame_end = frame_start.after(world.frame_rate.frametime(), world.frame_rate);
+        let pressed = pressed.clamp(frame_start, frame_end.after(-Second(1), world.frame_rate));
+        let swing = FrameOffset {
+            frame: pressed.frame + input_delay,
+            ..pressed
+        };
+        let recovered = match self.last_swing[handle] {
+            // Still to be simulated, so that attack will be in flight.
+            Some(last_swing) if last_swing.frame >= world.frame => {
+                let recovery = TEST_ATTACK.startup_time + TEST_ATTACK.recover_time;
+                swing > last_swing.after(recovery, world.frame_rate)
+            }
+            _ => world.players[handle].can_swing_at(swing),
+        };
+        if !recovered {
+            return None;
+        }
+        self.last_swing[handle] = Some(swing);
+        Some(swing)
     }
 }
-impl AddAssign<Second> for FrameOffset {
-    fn add_assign(&mut self, rhs: Second) {
-        *self = *self + rhs
-    }
+/// Plays some of the local handles in place of the keyboard.
+#[derive(Resource)]
+enum Autopilot {
+    Bot(Bot),
+    /// Plays both handles as they were recorded.
+    Replay(Replay),
 }
-impl FrameOffset {
-    fn now(last_tick_time: &LastTickTime) -> Self {
-        FrameOffset {
-            frame: last_tick_time.frame,
-            offset: 0,
-            // offset: Unorm64::from_f64(last_tick_time.instant.elapsed().as_secs_f64()).0,
-            // offset: 0,
-        } + Second(last_tick_time.instant.elapsed().as_secs_f64().min(1.0))
-        // + Second(last_tick_time.instant.elapsed().as_secs_f64())
-    }
-    fn get_offset_seconds(&self, future: &Self) -> Second {
-        let self_frame = (self.frame as f64 + Unorm64(self.offset).to_f64()) * FRAMETIME;
-        let future_frame = (future.frame as f64 + Unorm64(future.offset).to_f64()) * FRAMETIME;
 
-        Second(future_frame - self_frame)
+impl Autopilot {
+    fn controls(&self, handle: PlayerHandle) -> bool {
+        match self {
+            Autopilot::Bot(bot) => bot.handle() == handle,
+            Autopilot::Replay(_) => true,
+        }
+    }
+    /// The input for the frame after `world`.
+    fn input(&mut self, handle: PlayerHandle, world: &WorldSnapshot) -> SendInput {
+        match self {
+            Autopilot::Bot(bot) => bot.input(world),
+            Autopilot::Replay(replay) => replay.input(world.frame + 1, handle),
+        }
     }
-    // fn from_instant(instant: Instant, last_tick_time: &LastTickTime) -> Self {
-    //     let raw_offset = if last_tick_time.frame_instant > instant {
-    //         last_tick_time
-    //             .frame_instant
-    //             .duration_since(instant)
-    //             .as_secs_f64()
-    //     } else {
-    //         -instant
-    //             .duration_since(last_tick_time.frame_instant)
-    //             .as_secs_f64()
-    //     };
-    //     let frametime_seconds = FRAMETIME as f64 / 1000.0;
-    //     let frame_offset = raw_offset / frametime_seconds;
-    //     let remaining_offset = raw_offset.rem_euclid(frametime_seconds);
-    //     let frame = last_tick_time.frame_number + frame_offset as usize;
-    //     Self {
-    //         frame,
-    //         offset: Unorm64::from_f64(remaining_offset).0,
-    //     }
-    // }
-    // fn to_instant(&self, last_tick_time: &LastTickTime) -> Instant {
-    //     let offset = Unorm64(self.offset).to_f64();
-    //     let frame_delta = last_tick_time.frame_number as f64 - self.frame as f64;
-    //     let frame_delta = (frame_delta + offset) * (FRAMETIME as f64 / 1000.0);
-    //     if frame_delta > 0.0 {
-    //         last_tick_time.frame_instant + Duration::from_secs_f64(frame_delta)
-    //     } else {
-    //         last_tick_time.frame_instant - Duration::from_secs_f64(frame_delta.abs())
-    //     }
-    // }
-    // fn get_offset_seconds(&self, other: &Self, last_tick_time: &LastTickTime) -> f64 {
-    //     let self_instant = self.to_instant(last_tick_time);
-    //     let other_instant = other.to_instant(last_tick_time);
-    //     if self_instant > other_instant {
-    //         self_instant.duration_since(other_instant).as_secs_f64()
-    //     } else {
-    //         -other_instant.duration_since(self_instant).as_secs_f64()
-    //     }
-    // }
-    fn is_valid(&self) -> bool {
-        self.frame != usize::MAX
+    /// Whether `handle` is to be treated as gone when simulating `frame`, as they were
+    /// when it was recorded.
+    fn has_left(&self, frame: usize, handle: PlayerHandle) -> bool {
+        match self {
+            Autopilot::Bot(_) => false,
+            Autopilot::Replay(replay) => replay.has_left(frame, handle),
+        }
     }
 }
 
-#[derive(Component, Clone, Debug, Hash)]
-struct Player {
-    current_attack: Option<Attack>,
-    attack_start_time: FrameOffset,
-    attack_recover_time: FrameOffset,
-    last_defend_result: i64,
-    stamina: Unorm64,
-    final_clash_lives: u8,
-    final_clash_last_swing: Option<FrameOffset>,
+/// Everything that decides the local handles' inputs.
+#[derive(SystemParam)]
+struct InputSources<'w> {
+    local_input: ResMut<'w, LocalInput>,
+    autopilot: Option<ResMut<'w, Autopilot>>,
 }
-impl Player {
-    fn swing(
+
+impl InputSources<'_> {
+    /// The input for `input_delay` frames after the frame after `world`, forgetting the
+    /// keyboard input it used.
+    fn take(
         &mut self,
-        other: &mut Self,
-        frame_offset: FrameOffset,
-        attack: Attack,
-    ) -> Option<f64> {
-        self.attack_start_time = frame_offset;
-        self.attack_recover_time = frame_offset + attack.startup_time + attack.recover_time;
-
-        self.current_attack = Some(attack);
-
-        let defend_time = frame_offset;
-        let impact_time = other.attack_start_time + other.current_attack.as_ref()?.startup_time;
-
-        let defend_time_offset = defend_time.get_offset_seconds(&impact_time);
-        other.current_attack = None;
-        self.last_defend_result = Inorm64::from_f64(dbg!(defend_time_offset.0)).0;
-        Some(defend_time_offset.0)
-    }
-    fn take_final_clash_life(&mut self) {
-        // self.final_clash_last_swing = None;
-        if self.final_clash_lives > 0 {
-            self.final_clash_lives -= 1;
+        handle: PlayerHandle,
+        world: &WorldSnapshot,
+        input_delay: usize,
+    ) -> SendInput {
+        match &mut self.autopilot {
+            Some(autopilot) if autopilot.controls(handle) => autopilot.input(handle, world),
+            _ => self.local_input.take(handle, world, input_delay),
         }
     }
+    fn has_left(&self, frame: usize, handle: PlayerHandle) -> bool {
+        self.autopilot
+            .as_ref()
+            .is_some_and(|autopilot| autopilot.has_left(frame, handle))
+    }
 }
 
-// #[derive(Component, Debug, Clone)]
-// struct AnimatedAtlas {
-//     atlas: TextureAtlas,
-// }
-
-#[derive(Debug, Resource)]
-struct LocalInput {
-    attacking: Option<FrameOffset>,
-    // defending: Option<Instant>,
-}
 #[derive(Debug, Resource, AssetCollection)]
 struct AtlasLoader {
     #[asset(texture_atlas(tile_size_x = 56.0, tile_size_y = 36.0))]
@@ -287,6 +210,7 @@ struct AtlasLoader {
 
 #[derive(Resource)]
 struct AnimationLibrary {
+    #[allow(dead_code)]
     attack: Handle<Animation>,
     counter_attack: Handle<Animation>,
     idle: Handle<Animation>,
@@ -324,36 +248,6 @@ enum AnimationTriggerType {
     // Loop,
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone, PartialEq, Pod, Zeroable)]
-struct SendInput {
-    attacking: FrameOffset,
-    // defending: FrameOffset,
-}
-
-#[derive(Debug)]
-struct GGRSConfig;
-impl Config for GGRSConfig {
-    type Input = SendInput;
-    type State = WorldSnapshot;
-    type Address = SocketAddr;
-}
-
-#[derive(Resource, Clone, Hash)]
-struct FinalClash {
-    next_clash: Option<FrameOffset>,
-}
-
-#[derive(Clone, Hash)]
-struct WorldSnapshot {
-    players: [Player; 2],
-    final_clash: FinalClash,
-    game_state: GameState,
-}
-
-#[derive(Resource)]
-struct Session(P2PSession<GGRSConfig>);
-
 #[derive(Resource, AssetCollection)]
 struct SoundLibrary {
     #[asset(path = "block.ogg")]
@@ -369,23 +263,99 @@ struct SoundLibrary {
 #[derive(Resource)]
 struct SparkEffect(Handle<EffectAsset>);
 
+/// Marks the player this machine plays as, or watches from, and their half of the HUD.
 #[derive(Component)]
 pub struct LocalMarker;
 
+/// Which player handle's state a [`Player`] entity holds.
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerId(pub PlayerHandle);
+
 #[derive(Component)]
 pub struct FinalClashLives;
-pub struct BlockEvent(String);
+pub enum BlockEvent {
+    /// `handle` blocked on `frame`.
+    Blocked {
+        frame: usize,
+        handle: PlayerHandle,
+        text: &'static str,
+    },
+    /// The block predicted on `frame` never happened.
+    Cancelled { frame: usize, handle: PlayerHandle },
+}
 pub enum GameEvent {
-    GameOver { loser: Option<PlayerHandle> },
+    GameOver {
+        loser: Option<PlayerHandle>,
+        /// The loser left rather than lost.
+        forfeit: bool,
+    },
+    /// The predicted game over never happened.
+    GameOverCancelled,
 }
-#[derive(Clone, Hash, Resource, Default, PartialEq, Eq, Debug)]
-pub enum GameState {
-    #[default]
-    Playing,
-    FinalClash,
-    Over,
+
+#[derive(Component)]
+struct GameOverText;
+
+/// How the connection to the other player, or to the host when spectating, is doing.
+#[derive(Resource, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Exchanging the first few packets; `progress` is how many of how many went through.
+    Synchronizing {
+        progress: Option<(u32, u32)>,
+    },
+    Connected,
+    /// Nothing has arrived for a while, and the peer will be dropped if nothing does.
+    Interrupted,
+    Disconnected,
+}
+
+/// What the network stats overlay shows.
+#[derive(Resource, Default)]
+pub struct NetStats {
+    /// The connection to the other player, or to the host when spectating, once
+    /// synchronized.
+    pub network: Option<NetworkStats>,
+    /// When each recent rollback happened, oldest first.
+    rollbacks: VecDeque<Instant>,
+    /// The most frames resimulated at once this match.
+    pub deepest_rollback: usize,
+    pub rejected_packets: RejectedPackets,
 }
 
+impl NetStats {
+    fn record_rollback(&mut self, depth: usize) {
+        let now = Instant::now();
+        while self
+            .rollbacks
+            .front()
+            .is_some_and(|&rollback| now - rollback > Duration::from_secs(1))
+        {
+            self.rollbacks.pop_front();
+        }
+        self.rollbacks.push_back(now);
+        self.deepest_rollback = self.deepest_rollback.max(depth);
+    }
+    /// How many rollbacks happened in the last second, however long ago the last one was.
+    pub fn rollbacks_per_second(&self) -> usize {
+        let now = Instant::now();
+        let recent = self
+            .rollbacks
+            .iter()
+            .rev()
+            .take_while(|&&rollback| now - rollback <= Duration::from_secs(1));
+        recent.count()
+    }
+}
+
+#[derive(Component)]
+struct BlockSpark {
+    frame: usize,
+}
+
+/// Sounds started by effects that a rollback may still cancel.
+#[derive(Resource, Default)]
+struct EffectSounds(Vec<(Effect, Handle<AudioSink>)>);
+
 #[derive(States, Debug, Hash, PartialEq, Eq, Default, Clone, Copy)]
 pub enum AssetLoadingState {
     #[default]
@@ -397,20 +367,93 @@ pub enum AssetLoadingState {
 // struct LoadedUpdateSet;
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let mut frame_rate = cli.frame_rate();
 
     let mut app = App::new();
 
-    let session_builder = SessionBuilder::<GGRSConfig>::new()
-        .with_fps(FPS as usize)
-        .unwrap()
-        .add_player(PlayerType::Local, 0)
-        .unwrap()
-        .add_player(PlayerType::Remote(args.remote_addr), 1)
-        .unwrap();
-
-    let udp_socket = UdpNonBlockingSocket::bind_to_port(args.local_port).unwrap();
-    let session = session_builder.start_p2p_session(udp_socket).unwrap();
+    match &cli.mode {
+        Mode::Local {
+            bot: Some(difficulty),
+        } => {
+            app.insert_resource(Autopilot::Bot(Bot::new(
+                1,
+                difficulty.timing(),
+                clock_seed(),
+            )));
+        }
+        // The computer plays handle 1 so that blocks and final clashes get resimulated too.
+        &Mode::Synctest { bot, seed, .. } => {
+            let seed = seed.unwrap_or_else(clock_seed);
+            println!("Sync test bot seed: {seed}");
+            app.insert_resource(Autopilot::Bot(Bot::new(1, bot.timing(), seed)));
+        }
+        Mode::Replay { path } => match load_replay(path) {
+            Ok(replay) => {
+                frame_rate = replay.rules.frame_rate().unwrap();
+                app.insert_resource(Autopilot::Replay(replay));
+            }
+            Err(err) => {
+                eprintln!("error: {}: {err}", path.display());
+                exit(1);
+            }
+        },
+        _ => {}
+    }
+    if let Some(path) = cli.record {
+        app.insert_resource(ReplayRecorder::new(path, frame_rate));
+    }
+    let session = match cli.mode {
+        Mode::Host {
+            remote_addr,
+            port,
+            spectators,
+            relay_session,
+            net,
+        } => Session::p2p(P2PSettings {
+            local_handle: 0,
+            local_port: port,
+            remote_addr,
+            spectators,
+            relay_session,
+            net: net.settings(frame_rate),
+        }),
+        Mode::Join {
+            host_addr,
+            port,
+            relay_session,
+            net,
+        } => Session::p2p(P2PSettings {
+            local_handle: 1,
+            local_port: port,
+            remote_addr: host_addr,
+            spectators: vec![],
+            relay_session,
+            net: net.settings(frame_rate),
+        }),
+        Mode::Lobby {
+            lobby_addr,
+            room,
+            port,
+            net,
+        } => Session::lobby(port, lobby_addr, room, net.settings(frame_rate)),
+        Mode::Spectate {
+            host_addr,
+            port,
+            timeouts,
+            auth,
+        } => Session::spectator(port, host_addr, timeouts.timeouts(), auth.key(), frame_rate),
+        Mode::Local { .. } | Mode::Replay { .. } => Ok(Session::local(frame_rate)),
+        Mode::Synctest { check_distance, .. } => Session::synctest(check_distance, frame_rate),
+    };
+    let session = session.unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        exit(1);
+    });
+    let connection_status = match session.current_state() {
+        SessionState::Synchronizing => ConnectionStatus::Synchronizing { progress: None },
+        SessionState::Running => ConnectionStatus::Connected,
+    };
     app.add_plugins(
         DefaultPlugins
             .set(WindowPlugin {
@@ -431,11 +474,12 @@ fn main() {
     .add_asset::<Animation>()
     .add_plugin(HanabiPlugin)
     .add_plugin(LogDiagnosticsPlugin::default())
-    .add_plugin(FrameTimeDiagnosticsPlugin::default())
+    .add_plugin(FrameTimeDiagnosticsPlugin)
     .add_plugin(GUI)
     .add_plugin(Sprite3dPlugin)
     .add_event::<BlockEvent>()
     .add_event::<GameEvent>()
+    .add_event::<EffectEvent>()
     .add_loading_state(
         LoadingState::new(AssetLoadingState::Loading).continue_to_state(AssetLoadingState::Done),
     )
@@ -445,28 +489,50 @@ fn main() {
     .add_system((setup_players).in_schedule(OnEnter(AssetLoadingState::Done)))
     .add_systems(
         (
-            input,
-            rollback_system.run_if(
-                on_timer(Duration::from_secs_f64(FRAMETIME))
-                    .and_then(not(resource_exists_and_equals(GameState::Over))),
+            input.run_if(session_running),
+            pace_ticks,
+            play_effects,
+            release_confirmed_sounds.after(play_effects),
+            update_net_stats.run_if(on_timer(Duration::from_secs(1))),
+            save_replay.run_if(
+                resource_exists::<ReplayRecorder>().and_then(on_timer(Duration::from_secs(1))),
             ),
-            network_stats.run_if(on_timer(Duration::from_secs_f64(5.0))),
             poll_clients,
-            handle_game_events.run_if(not(resource_exists_and_equals(GameState::Over))),
+            dump_desyncs.after(poll_clients),
+            follow_viewpoint.after(poll_clients),
+            update_connection_status.after(poll_clients),
+            handle_game_events,
+            quit_after_disconnect,
             update_animated_atlas,
             block_sparks,
             update_animations,
         )
             .distributive_run_if(in_state(AssetLoadingState::Done)),
     )
-    .insert_resource(Session(session))
+    .insert_resource(session)
+    .add_event::<SessionEvent>()
+    .add_system(
+        rollback_system
+            .in_schedule(CoreSchedule::FixedUpdate)
+            .run_if(in_state(AssetLoadingState::Done))
+            .run_if(session_running),
+    )
+    .insert_resource(FixedTime::new(Duration::from_secs_f64(
+        frame_rate.frametime().as_secs_f64(),
+    )))
     .insert_resource(LastTickTime {
         frame: 0,
         instant: Instant::now(),
+        frame_rate,
     })
-    .insert_resource(FinalClash { next_clash: None })
-    .insert_resource(LocalInput { attacking: None })
-    .insert_resource(GameState::default())
+    .insert_resource(WorldSnapshot::default().final_clash)
+    .init_resource::<LocalInput>()
+    .init_resource::<EffectLedger>()
+    .init_resource::<EffectSounds>()
+    .init_resource::<SnapshotHistory>()
+    .init_resource::<NetStats>()
+    .insert_resource(connection_status)
+    .insert_resource(WorldSnapshot::default().game_state)
     .run();
 }
 
@@ -474,9 +540,17 @@ fn main() {
 //     last_tick_time.frame_offset += Second(time.delta_seconds_f64());
 // }
 
-fn network_stats(session: Res<Session>) {
-    // dbg!(session.0.network_stats(0));
-    println!("{:?}", session.0.network_stats(1));
+/// A seed that differs from run to run.
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+fn update_net_stats(session: Res<Session>, mut net_stats: ResMut<NetStats>) {
+    net_stats.network = session.network_stats();
+    net_stats.rejected_packets = session.rejected_packets();
 }
 
 // fn setup_audio(mut commands: Commands, asset_server: Res<AssetServer>) {
@@ -490,10 +564,10 @@ fn network_stats(session: Res<Session>) {
 
 fn setup_players(
     mut commands: Commands,
-    last_tick_time: Res<LastTickTime>,
     mut sprite_params: Sprite3dParams,
     atlas: Res<AtlasLoader>,
     mut animation_asset: ResMut<Assets<Animation>>,
+    session: Res<Session>,
     // mut fighter_sprites: ResMut<FighterSprites>,
     // texture_atlas_assets: ResMut<Assets<TextureAtlas>>
 ) {
@@ -522,16 +596,19 @@ fn setup_players(
 
     let counter_attack = animation_asset.add({
         let mut animation = vec![];
-        animation.extend(repeat(Frame {
-            mesh: sprite_params
-                .sr
-                .mesh_cache
-                .get(&defend_bundle.params.atlas[0])
-                .unwrap()
-                .clone(),
-            material: defend_bundle.pbr.material.clone(),
-            trigger: None,
-        }).take(3));
+        animation.extend(repeat_n(
+            Frame {
+                mesh: sprite_params
+                    .sr
+                    .mesh_cache
+                    .get(&defend_bundle.params.atlas[0])
+                    .unwrap()
+                    .clone(),
+                material: defend_bundle.pbr.material.clone(),
+                trigger: None,
+            },
+            3,
+        ));
 
         for i in 0..attack_bundle.params.atlas.len() {
             animation.push(Frame {
@@ -589,16 +666,12 @@ fn setup_players(
         idle: idle.clone(),
     });
 
+    let local_handle = session.viewpoint();
+    let remote_handle = 1 - local_handle;
+    let players = WorldSnapshot::default().players;
     commands
-        .spawn(Player {
-            current_attack: None,
-            attack_start_time: FrameOffset::now(&last_tick_time),
-            attack_recover_time: FrameOffset::now(&last_tick_time),
-            last_defend_result: 0,
-            stamina: Unorm64(u64::MAX),
-            final_clash_last_swing: None,
-            final_clash_lives: FINAL_CLASH_LIVES,
-        })
+        .spawn(players[local_handle].clone())
+        .insert(PlayerId(local_handle))
         .insert(LocalMarker)
         .insert(PbrBundle {
             transform: Transform::from_translation(Vec3::new(-2.0, 0.0, 0.0)),
@@ -607,21 +680,14 @@ fn setup_players(
         .insert(Animated {
             // animation: idle.clone(),
             // current_frame: 0,
-            // start: FrameOffset::now(&last_tick_time),
+            // start: last_tick_time.now(),
             // next: VecDeque::new(),
-            previous_frame: usize::MAX
+            previous_frame: usize::MAX,
         });
 
     commands
-        .spawn(Player {
-            current_attack: None,
-            attack_start_time: FrameOffset::now(&last_tick_time),
-            attack_recover_time: FrameOffset::now(&last_tick_time),
-            last_defend_result: 0,
-            stamina: Unorm64(u64::MAX),
-            final_clash_last_swing: None,
-            final_clash_lives: FINAL_CLASH_LIVES,
-        })
+        .spawn(players[remote_handle].clone())
+        .insert(PlayerId(remote_handle))
         .insert(PbrBundle {
             transform: Transform::from_scale(Vec3::new(-1.0, 1.0, 1.0))
                 .with_translation(Vec3::new(2.0, 0.0, 0.0)),
@@ -630,9 +696,9 @@ fn setup_players(
         .insert(Animated {
             // animation: idle.clone(),
             // current_frame: 0,
-            // start: FrameOffset::now(&last_tick_time),
+            // start: last_tick_time.now(),
             // next: VecDeque::new(),
-            previous_frame: usize::MAX
+            previous_frame: usize::MAX,
         });
 }
 
@@ -681,76 +747,247 @@ fn setup_particles(
     ));
 }
 
+/// The attack bindings of each player handle on this machine.
+#[derive(SystemParam)]
+struct AttackButtons<'w> {
+    keyboard_input: Res<'w, bevy::input::Input<KeyCode>>,
+    gamepad_input: Res<'w, bevy::input::Input<GamepadButton>>,
+    gamepads: Res<'w, Gamepads>,
+}
+
+impl AttackButtons<'_> {
+    fn just_pressed(&self, handle: PlayerHandle) -> bool {
+        match handle {
+            0 => self.keyboard_input.just_pressed(KeyCode::A),
+            _ => {
+                self.keyboard_input.just_pressed(KeyCode::L)
+                    || self.gamepads.iter().any(|gamepad| {
+                        self.gamepad_input
+                            .just_pressed(GamepadButton::new(gamepad, GamepadButtonType::South))
+                    })
+            }
+        }
+    }
+}
+
 fn input(
-    keyboard_input: Res<bevy::input::Input<KeyCode>>,
+    attack_buttons: AttackButtons,
     mut local_input: ResMut<LocalInput>,
-    local_player_query: Query<&mut Player, With<LocalMarker>>,
     last_tick_time: Res<LastTickTime>,
+    session: Res<Session>,
+    autopilot: Option<Res<Autopilot>>,
+) {
+    // Whether the swing is allowed is decided once it's taken for a frame, against the
+    // world it will be simulated in.
+    for handle in human_handles(&session, autopilot.as_deref()) {
+        if attack_buttons.just_pressed(handle) {
+            local_input.attacking[handle] = Some(last_tick_time.now());
+        }
+    }
+}
+
+/// The local players that someone at this machine controls.
+fn human_handles<'a>(
+    session: &'a Session,
+    autopilot: Option<&'a Autopilot>,
+) -> impl Iterator<Item = PlayerHandle> + 'a {
+    session
+        .local_handles()
+        .iter()
+        .copied()
+        .filter(move |&handle| autopilot.is_none_or(|autopilot| !autopilot.controls(handle)))
+}
+
+fn save_replay(mut recorder: ResMut<ReplayRecorder>) {
+    if let Err(err) = recorder.save() {
+        eprintln!("Could not write replay: {err}");
+    }
+}
+
+fn session_running(session: Res<Session>) -> bool {
+    session.current_state() == SessionState::Running
+}
+
+/// Frames are simulated in the fixed update schedule, as many times per render frame as
+/// fit in the time that passed. While this peer is ahead of the other they are stretched a
+/// little, rather than stopping the whole app to wait.
+fn pace_ticks(
+    session: Res<Session>,
+    last_tick_time: Res<LastTickTime>,
+    mut fixed_time: ResMut<FixedTime>,
+) {
+    let mut frametime = last_tick_time.frame_rate.frametime().as_secs_f64();
+    if session.frames_ahead() > 0 {
+        frametime *= AHEAD_SLOWDOWN;
+    }
+    fixed_time.period = Duration::from_secs_f64(frametime);
+}
+
+fn poll_clients(mut session: ResMut<Session>, mut ev_session: EventWriter<SessionEvent>) {
+    session.poll_remote_clients();
+    ev_session.send_batch(session.events().into_iter().map(SessionEvent));
+}
+
+/// Keeps the left player the local one, for sessions that only learn which handle that is
+/// once connected. Both players are still in their starting state by then.
+fn follow_viewpoint(
+    session: Res<Session>,
+    mut players: Query<(&mut PlayerId, Option<&LocalMarker>)>,
 ) {
-    let local_player = local_player_query.single();
-    if keyboard_input.just_pressed(KeyCode::A) {
-        if let Some(current_attack) = &local_player.current_attack {
-            let attack_recovered = local_player.attack_start_time
-                + current_attack.startup_time
-                + current_attack.recover_time;
-            if FrameOffset::now(&last_tick_time) > attack_recovered {
-                local_input.attacking = Some(FrameOffset::now(&last_tick_time));
+    let viewpoint = session.viewpoint();
+    for (mut id, local) in &mut players {
+        let handle = match local {
+            Some(_) => viewpoint,
+            None => 1 - viewpoint,
+        };
+        if id.0 != handle {
+            id.0 = handle;
+        }
+    }
+}
+
+/// Only desyncs earlier than any dumped yet are dumped. The frames checked after one
+/// differ too, because of it, but a checksum sent again can report an earlier frame late.
+fn dump_desyncs(
+    mut ev_session: EventReader<SessionEvent>,
+    snapshot_history: Res<SnapshotHistory>,
+    mut earliest_dumped: Local<Option<ggrs::Frame>>,
+) {
+    for event in ev_session.iter() {
+        if let GGRSEvent::DesyncDetected {
+            frame,
+            local_checksum,
+            remote_checksum,
+            addr,
+        } = event.0
+        {
+            println!("Desync on frame {frame} with {addr}");
+            if earliest_dumped.is_some_and(|earliest| earliest <= frame) {
+                continue;
+            }
+            *earliest_dumped = Some(frame);
+            // The working directory, where whoever ran the game will look.
+            match dump_desync(
+                &snapshot_history,
+                Path::new("."),
+                frame,
+                local_checksum,
+                remote_checksum,
+                addr,
+            ) {
+                Ok(path) => println!("Wrote local snapshot to {}", path.display()),
+                Err(err) => eprintln!("Could not write desync snapshot: {err}"),
             }
-        } else {
-            local_input.attacking = Some(FrameOffset::now(&last_tick_time));
         }
     }
 }
 
-fn poll_clients(mut session: ResMut<Session>) {
-    session.0.poll_remote_clients();
+fn update_connection_status(
+    mut ev_session: EventReader<SessionEvent>,
+    session: Res<Session>,
+    mut connection_status: ResMut<ConnectionStatus>,
+) {
+    for event in ev_session.iter() {
+        match event.0 {
+            // Spectators coming and going don't matter to the match.
+            GGRSEvent::Synchronizing { addr, total, count } if session.is_peer(addr) => {
+                *connection_status = ConnectionStatus::Synchronizing {
+                    progress: Some((count, total)),
+                };
+            }
+            GGRSEvent::Synchronized { addr } | GGRSEvent::NetworkResumed { addr }
+                if session.is_peer(addr) =>
+            {
+                *connection_status = ConnectionStatus::Connected;
+            }
+            GGRSEvent::NetworkInterrupted { addr, .. } if session.is_peer(addr) => {
+                *connection_status = ConnectionStatus::Interrupted;
+            }
+            GGRSEvent::Disconnected { addr } if session.is_peer(addr) => {
+                println!("{addr} disconnected");
+                *connection_status = ConnectionStatus::Disconnected;
+            }
+            GGRSEvent::Disconnected { addr } => println!("Spectator {addr} disconnected"),
+            _ => {}
+        }
+    }
+}
+
+/// Once the other side is gone there is nothing left to wait for.
+fn quit_after_disconnect(
+    keyboard_input: Res<bevy::input::Input<KeyCode>>,
+    connection_status: Res<ConnectionStatus>,
+    mut ev_exit: EventWriter<AppExit>,
+) {
+    if *connection_status == ConnectionStatus::Disconnected
+        && keyboard_input.just_pressed(KeyCode::Escape)
+    {
+        ev_exit.send(AppExit);
+    }
 }
 
 fn handle_game_events(
     mut commands: Commands,
     mut ev_game: EventReader<GameEvent>,
-    mut state: ResMut<GameState>,
+    game_over_text: Query<Entity, With<GameOverText>>,
     roboto: Res<Roboto>,
+    session: Res<Session>,
+    autopilot: Option<Res<Autopilot>>,
 ) {
     for event in ev_game.into_iter() {
         match event {
-            GameEvent::GameOver { loser } => {
+            GameEvent::GameOver { loser, forfeit } => {
                 println!("Game over!");
-                let text;
-                if loser.is_none() {
-                    text = "Tie"
-                } else if loser.unwrap() == 0 {
-                    text = "Defeat"
-                } else {
-                    text = "Victory"
-                }
-                *state = GameState::Over;
-
-                commands.spawn(TextBundle {
-                    text: Text::from_section(
-                        text,
-                        TextStyle {
-                            font: roboto.0.clone(),
-                            font_size: 96.0,
-                            color: Color::WHITE,
-                            ..Default::default()
-                        },
-                    )
-                    .with_alignment(TextAlignment::Center),
-                    style: Style {
-                        size: Size::all(Val::Percent(50.0)),
-                        justify_content: JustifyContent::Center,
-                        align_content: AlignContent::Center,
-                        position_type: PositionType::Absolute,
-                        position: UiRect {
-                            top: Val::Percent(10.0),
-                            left: Val::Percent(10.0),
+                // Only talk about winning and losing when there's one player watching. The
+                // viewpoint is the player on the left.
+                let lost = *loser == Some(session.viewpoint());
+                let text = match (
+                    loser,
+                    human_handles(&session, autopilot.as_deref()).count() == 1,
+                    forfeit,
+                ) {
+                    (None, _, _) => "Tie",
+                    (Some(_), true, false) if lost => "Defeat",
+                    (Some(_), true, false) => "Victory",
+                    (Some(_), true, true) if lost => "Defeat by forfeit",
+                    (Some(_), true, true) => "Victory by forfeit",
+                    (Some(_), false, false) if lost => "Right wins",
+                    (Some(_), false, false) => "Left wins",
+                    (Some(_), false, true) if lost => "Right wins by forfeit",
+                    (Some(_), false, true) => "Left wins by forfeit",
+                };
+
+                commands
+                    .spawn(TextBundle {
+                        text: Text::from_section(
+                            text,
+                            TextStyle {
+                                font: roboto.0.clone(),
+                                font_size: 96.0,
+                                color: Color::WHITE,
+                            },
+                        )
+                        .with_alignment(TextAlignment::Center),
+                        style: Style {
+                            size: Size::all(Val::Percent(50.0)),
+                            justify_content: JustifyContent::Center,
+                            align_content: AlignContent::Center,
+                            position_type: PositionType::Absolute,
+                            position: UiRect {
+                                top: Val::Percent(10.0),
+                                left: Val::Percent(10.0),
+                                ..Default::default()
+                            },
                             ..Default::default()
                         },
                         ..Default::default()
-                    },
-                    ..Default::default()
-                });
+                    })
+                    .insert(GameOverText);
+            }
+            GameEvent::GameOverCancelled => {
+                for entity in game_over_text.iter() {
+                    commands.entity(entity).despawn();
+                }
             }
         }
     }
@@ -763,9 +1000,9 @@ fn update_animated_atlas(
     for (mut atlas, player) in atlas_query.iter_mut() {
         let frame = ((player
             .attack_start_time
-            .get_offset_seconds(&FrameOffset::now(&last_tick_time))
-            .0
-            / FRAMETIME) as usize)
+            .get_offset_seconds(&last_tick_time.now(), last_tick_time.frame_rate)
+            .as_secs_f64()
+            * ANIMATION_FPS) as usize)
             .min(atlas.atlas.len())
             % atlas.atlas.len();
         if frame != atlas.index {
@@ -785,7 +1022,7 @@ fn update_animated_atlas(
 //             *material_handle= next.material.clone();
 //             animated.next = None;
 //         }
-//         let frame = ((animated.start.get_offset_seconds(&FrameOffset::now(&last_tick_time)).0 / FRAMETIME) as usize).min(atlas.atlas.len()) % atlas.atlas.len();
+//         let frame = ((animated.start.get_offset_seconds(&last_tick_time.now()).0 / FRAMETIME) as usize).min(atlas.atlas.len()) % atlas.atlas.len();
 //         if frame != atlas.index{ //For change detection purposes
 //             atlas.index = frame;
 //         }
@@ -812,21 +1049,21 @@ fn update_animated_atlas(
 //                 AnimationQueueItem::Immediate(next_animation) => {
 //                     animated.animation = next_animation.clone();
 //                     animation_changed = true;
-//                     animated.start = FrameOffset::now(&last_tick_time);
+//                     animated.start = last_tick_time.now();
 //                     animated.next.pop_front();
 //                 },
 //                 AnimationQueueItem::UponCompletion(next_animation) => {
 //                     let animation = animation_assets.get(&animated.animation).unwrap();
-//                     if (animated.start.get_offset_seconds(&FrameOffset::now(&last_tick_time)).0 / FRAMETIME) as usize > animation.0.len(){
+//                     if (animated.start.get_offset_seconds(&last_tick_time.now()).0 / FRAMETIME) as usize > animation.0.len(){
 //                         animated.animation = next_animation.clone();
 //                         animation_changed = true;
-//                         animated.start = FrameOffset::now(&last_tick_time);
+//                         animated.start = last_tick_time.now();
 //                         animated.next.pop_front();
 //                     }
 //                 },
 //                 // AnimationQueueItem::Idle(next_animation) => {
 //                 //     let animation = animation_assets.get(&animated.animation).unwrap();
-//                 //     if (animated.start.get_offset_seconds(&FrameOffset::now(&last_tick_time)).0 / FRAMETIME) as usize > animation.0.len(){
+//                 //     if (animated.start.get_offset_seconds(&last_tick_time.now()).0 / FRAMETIME) as usize > animation.0.len(){
 //                 //         animated.animation = next_animation.clone();
 //                 //         animation_changed = true;
 //                 //     }
@@ -836,7 +1073,7 @@ fn update_animated_atlas(
 
 //         let animation_handle = animated.animation.clone();
 //         let animation = animation_assets.get(&animated.animation).unwrap();
-//         let frame = ((animated.start.get_offset_seconds(&FrameOffset::now(&last_tick_time)).0 / FRAMETIME) as usize).min(animation.0.len()) % animation.0.len();
+//         let frame = ((animated.start.get_offset_seconds(&last_tick_time.now()).0 / FRAMETIME) as usize).min(animation.0.len()) % animation.0.len();
 //         if frame != animated.current_frame || animation_changed{ //For change detection purposes
 //             animated.current_frame = frame;
 //             let frame = &animation.0[animated.current_frame];
@@ -851,7 +1088,7 @@ fn update_animated_atlas(
 //                         println!("loop");
 //                         if animated.next.len() == 0{
 //                             println!("loop reset");
-//                             // animated.start = FrameOffset::now(&last_tick_time);
+//                             // animated.start = last_tick_time.now();
 //                             animated.next.push_back(AnimationQueueItem::UponCompletion(animation_handle.clone()));
 //                         }
 //                     },
@@ -885,15 +1122,15 @@ fn update_animations(
                 animation_library.counter_attack.clone(),
                 player
                     .attack_start_time
-                    .get_offset_seconds(&FrameOffset::now(&last_tick_time))
-                    .0,
+                    .get_offset_seconds(&last_tick_time.now(), last_tick_time.frame_rate)
+                    .as_secs_f64(),
             )
         } else {
             (animation_library.idle.clone(), time.elapsed_seconds_f64())
         };
         let animation = animation_assets.get(&animation).unwrap();
-        let frame = (progress_seconds / FRAMETIME) as usize % animation.0.len();
-        if animated.previous_frame != frame{
+        let frame = (progress_seconds * ANIMATION_FPS) as usize % animation.0.len();
+        if animated.previous_frame != frame {
             animated.previous_frame = frame;
             let frame = &animation.0[frame];
             *mesh_handle = frame.mesh.clone();
@@ -906,7 +1143,6 @@ fn update_animations(
                 }
             }
         }
-
     }
 }
 
@@ -927,338 +1163,266 @@ fn block_sparks(
     spark_effect: Res<SparkEffect>,
     mut ev_block: EventReader<BlockEvent>,
     remote_player: Query<&Transform, (With<Player>, Without<LocalMarker>)>,
-    local_player: Query<&Transform, (With<Player>, With<LocalMarker>)>,
+    local_player: Query<(&Transform, &PlayerId), With<LocalMarker>>,
+    sparks: Query<(Entity, &BlockSpark)>,
 ) {
-    for _ in ev_block.iter() {
-        let transform = EaseValue(remote_player.single().clone())
-            .lerp(&EaseValue(local_player.single().clone()), &0.5)
-            .0;
-        commands.spawn(ParticleEffectBundle {
-            effect: ParticleEffect::new(spark_effect.0.clone()),
-            transform,
-            ..Default::default()
-        });
+    let (local_transform, local_id) = local_player.single();
+    for event in ev_block.iter() {
+        match *event {
+            BlockEvent::Blocked { frame, handle, .. } if handle == local_id.0 => {
+                let transform = EaseValue(*remote_player.single())
+                    .lerp(&EaseValue(*local_transform), &0.5)
+                    .0;
+                commands
+                    .spawn(ParticleEffectBundle {
+                        effect: ParticleEffect::new(spark_effect.0.clone()),
+                        transform,
+                        ..Default::default()
+                    })
+                    .insert(BlockSpark { frame });
+            }
+            BlockEvent::Blocked { .. } => {}
+            BlockEvent::Cancelled { frame, .. } => {
+                for (entity, spark) in sparks.iter() {
+                    if spark.frame == frame {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+        }
     }
 }
 
-fn rollback_system(
-    mut session: ResMut<Session>,
-    mut local_input: ResMut<LocalInput>,
-    mut last_tick_time: ResMut<LastTickTime>,
-    mut local_player_query: Query<&mut Player, With<LocalMarker>>,
-    mut remote_player_query: Query<&mut Player, Without<LocalMarker>>,
-    mut final_clash: ResMut<FinalClash>,
-    // mut text_query: Query<&mut Text, With<BlockQualityIndicator>>,
-    mut ev_block: EventWriter<BlockEvent>,
-    mut ev_game: EventWriter<GameEvent>,
-    audio_library: Res<SoundLibrary>,
-    // animation_library: Res<AnimationLibrary>,
-    audio: Res<Audio>,
-    mut game_state: ResMut<GameState>,
-    // audio_sinks: Res<Assets<AudioSink>>,
-) {
-    let attacking = if let Some(attacking) = local_input.attacking {
-        attacking
-    } else {
-        FrameOffset {
-            frame: usize::MAX,
-            offset: 0,
+/// The parts of the ECS that mirror a [`WorldSnapshot`].
+#[derive(SystemParam)]
+struct SimWorld<'w, 's> {
+    last_tick_time: ResMut<'w, LastTickTime>,
+    players: Query<'w, 's, (&'static PlayerId, &'static mut Player)>,
+    final_clash: ResMut<'w, FinalClash>,
+    game_state: ResMut<'w, GameState>,
+}
+
+impl SimWorld<'_, '_> {
+    fn snapshot(&self) -> WorldSnapshot {
+        let player = |handle| {
+            let (_, player) = self.players.iter().find(|(id, _)| id.0 == handle).unwrap();
+            player.clone()
+        };
+        WorldSnapshot {
+            frame_rate: self.last_tick_time.frame_rate,
+            frame: self.last_tick_time.frame,
+            players: [player(0), player(1)],
+            final_clash: self.final_clash.clone(),
+            game_state: self.game_state.clone(),
         }
-    };
-    *local_input = LocalInput { attacking: None };
-    session
-        .0
-        .add_local_input(0, SendInput { attacking })
-        .unwrap();
+    }
+    fn restore(&mut self, world_snapshot: WorldSnapshot) {
+        for (id, mut player) in &mut self.players {
+            *player = world_snapshot.players[id.0].clone();
+        }
+        *self.final_clash = world_snapshot.final_clash;
+        *self.game_state = world_snapshot.game_state;
+        *self.last_tick_time = LastTickTime {
+            frame: world_snapshot.frame,
+            instant: Instant::now(),
+            frame_rate: world_snapshot.frame_rate,
+        };
+    }
+}
 
-    if session.0.frames_ahead() > 0 {
-        sleep(Duration::from_secs_f64(FRAMETIME))
+fn block_text(block_quality: f32) -> &'static str {
+    if block_quality == 1.0 {
+        "INHUMAN BLOCK"
+    } else if block_quality > 0.999 {
+        "Perfect Block"
+    } else if block_quality > 0.99 {
+        "Excellent Block"
+    } else if block_quality > 0.9 {
+        "Good Block"
+    } else if block_quality > 0.8 {
+        "Decent Block"
+    } else {
+        "Sloppy Block"
     }
+}
 
-    let advance_result = session.0.advance_frame();
-    if let Ok(session) = advance_result {
-        for request in session {
-            match request {
-                GGRSRequest::SaveGameState { cell, frame } => {
-                    assert_eq!(last_tick_time.frame as i32, frame);
-                    let world_snapshot = WorldSnapshot {
-                        players: [
-                            local_player_query.single().clone(),
-                            remote_player_query.single().clone(),
-                        ],
-                        final_clash: final_clash.clone(),
-                        game_state: game_state.clone(),
-                    };
-                    let mut hasher = DefaultHasher::new();
-                    world_snapshot.hash(&mut hasher);
-                    cell.save(
-                        last_tick_time.frame as i32,
-                        Some(world_snapshot),
-                        Some(hasher.finish().into()),
-                    )
-                }
-                GGRSRequest::LoadGameState { cell, frame } => {
-                    let world_snapshot: WorldSnapshot = cell.load().unwrap();
+/// What is kept about simulated frames to explain problems with the connection.
+#[derive(SystemParam)]
+struct NetDiagnostics<'w> {
+    snapshot_history: ResMut<'w, SnapshotHistory>,
+    net_stats: ResMut<'w, NetStats>,
+}
 
-                    *local_player_query.single_mut() = world_snapshot.players[0].clone();
-                    *remote_player_query.single_mut() = world_snapshot.players[1].clone();
+fn rollback_system(
+    mut session: ResMut<Session>,
+    mut input_sources: InputSources,
+    mut sim_world: SimWorld,
+    mut effect_ledger: ResMut<EffectLedger>,
+    mut ev_effect: EventWriter<EffectEvent>,
+    mut diagnostics: NetDiagnostics,
+    mut recorder: Option<ResMut<ReplayRecorder>>,
+) {
+    for handle in session.local_handles().to_vec() {
+        let input = input_sources.take(handle, &sim_world.snapshot(), session.input_delay());
+        session.add_local_input(handle, input).unwrap();
+    }
 
-                    // let frame_delta = frame - last_tick_time.frame_offset.frame as i32;
+    let requests = match session.advance_frame() {
+        Ok(requests) => requests,
+        Err(GGRSError::MismatchedChecksum { frame }) => panic!(
+            "sync test failed: resimulating frame {frame} gave a different checksum, so the \
+             simulation is not deterministic"
+        ),
+        // Too far ahead of the other player's inputs, so this frame waits for them.
+        Err(GGRSError::PredictionThreshold) => vec![],
+        Err(err) => {
+            eprintln!(
+                "Could not advance past frame {}: {err}",
+                sim_world.last_tick_time.frame
+            );
+            vec![]
+        }
+    };
+    for request in requests {
+        match request {
+            GGRSRequest::SaveGameState { cell, frame } => {
+                let world_snapshot = sim_world.snapshot();
+                assert_eq!(world_snapshot.frame as i32, frame);
+                let checksum = world_snapshot.checksum();
+                session.save_checksum(frame, checksum);
+                diagnostics.snapshot_history.save(world_snapshot.clone());
+                cell.save(frame, Some(world_snapshot), Some(checksum))
+            }
+            GGRSRequest::LoadGameState { cell, frame } => {
+                let depth = sim_world.last_tick_time.frame - frame as usize;
+                diagnostics.net_stats.record_rollback(depth);
+                sim_world.restore(cell.load().unwrap());
+            }
+            GGRSRequest::AdvanceFrame { inputs } => {
+                // A player who has left no longer swings, and loses once the frame is done.
+                // A replay says who had left, since its local session never disconnects.
+                let mut world_snapshot = sim_world.snapshot();
+                let frame = world_snapshot.frame + 1;
+                let disconnected = [0, 1].map(|handle| {
+                    inputs[handle].1 == InputStatus::Disconnected
+                        || input_sources.has_left(frame, handle)
+                });
+                let inputs = [0, 1].map(|handle| match disconnected[handle] {
+                    true => world_snapshot.players[handle].idle_input(),
+                    false => inputs[handle].0,
+                });
+                let mut events = simulate(&mut world_snapshot, inputs);
+                for handle in (0..2).filter(|&handle| disconnected[handle]) {
+                    events.extend(sim::forfeit(&mut world_snapshot, handle));
+                }
+                if let Some(recorder) = &mut recorder {
+                    recorder.record(world_snapshot.frame, inputs, disconnected);
+                }
+                ev_effect.send_batch(effect_ledger.record(world_snapshot.frame, events));
+                sim_world.restore(world_snapshot);
+            }
+        }
+    }
 
-                    // let new_offset = last_tick_time.frame_offset + Second(FRAMETIME * frame_delta as f64);
+    let confirmed_frame = session.confirmed_frame(sim_world.last_tick_time.frame as i32);
+    session.compare_checksums(confirmed_frame);
+    if confirmed_frame >= 0 {
+        for effect in effect_ledger.confirm(confirmed_frame as usize) {
+            if let SimEvent::InvalidSwing { handle, reason } = effect.event {
+                eprintln!(
+                    "Ignored a swing by player {handle} on frame {}: {reason}",
+                    effect.frame
+                );
+            }
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.confirm(confirmed_frame as usize);
+        }
+    }
+}
 
-                    *game_state = world_snapshot.game_state;
-                    *final_clash = world_snapshot.final_clash;
-                    *last_tick_time = LastTickTime {
-                        frame: frame as usize,
-                        instant: Instant::now(),
+fn play_effects(
+    mut ev_effect: EventReader<EffectEvent>,
+    mut ev_block: EventWriter<BlockEvent>,
+    mut ev_game: EventWriter<GameEvent>,
+    mut effect_sounds: ResMut<EffectSounds>,
+    audio_library: Res<SoundLibrary>,
+    audio: Res<Audio>,
+    audio_sinks: Res<Assets<AudioSink>>,
+) {
+    for effect_event in ev_effect.iter() {
+        match effect_event {
+            EffectEvent::Started(effect) => {
+                let mut play = |sound: &Handle<AudioSource>, volume: f32| {
+                    let sink = audio.play_with_settings(
+                        sound.clone(),
+                        PlaybackSettings {
+                            repeat: false,
+                            volume: volume * VOLUME_SCALE,
+                            speed: 1.0,
+                        },
+                    );
+                    effect_sounds
+                        .0
+                        .push((effect.clone(), audio_sinks.get_handle(sink)));
+                };
+                match effect.event {
+                    SimEvent::Block { handle, quality } => {
+                        let sound_block_quality = quality.powf(16.0);
+                        play(&audio_library.block, 1.0 - sound_block_quality);
+                        play(&audio_library.perfect_block, sound_block_quality);
+                        ev_block.send(BlockEvent::Blocked {
+                            frame: effect.frame,
+                            handle,
+                            text: block_text(quality),
+                        });
                     }
+                    SimEvent::FinalClashCut { .. } => play(&audio_library.flesh_cut, 1.0),
+                    SimEvent::FinalClashParry => play(&audio_library.block, 1.0),
+                    SimEvent::FinalClashBegan => println!("Beginning final clash"),
+                    // Only reported once confirmed, since prediction can produce them.
+                    SimEvent::InvalidSwing { .. } => {}
+                    SimEvent::GameOver { loser } => ev_game.send(GameEvent::GameOver {
+                        loser,
+                        forfeit: false,
+                    }),
+                    SimEvent::Forfeit { loser } => ev_game.send(GameEvent::GameOver {
+                        loser: Some(loser),
+                        forfeit: true,
+                    }),
                 }
-                GGRSRequest::AdvanceFrame { inputs } => {
-                    last_tick_time.frame += 1;
-                    last_tick_time.instant = Instant::now();
-                    if matches!(*game_state, GameState::FinalClash) {
-                        if final_clash.next_clash.is_none() {
-                            let now = FrameOffset::now(&last_tick_time);
-                            final_clash.next_clash = Some(now + CLASH_LENGTH);
-                        }
+            }
+            EffectEvent::Cancelled(effect) => {
+                effect_sounds.0.retain(|(sound_effect, sink)| {
+                    if sound_effect != effect {
+                        return true;
                     }
-
-                    for (handle, (received_input, status)) in inputs.into_iter().enumerate() {
-                        assert!(!matches!(status, InputStatus::Disconnected));
-                        let (
-                            mut current_player,
-                            mut other_player,
-                        ) = if handle == 0 {
-                            (
-                                local_player_query.single_mut(),
-                                remote_player_query.single_mut(),
-                            )
-                        } else {
-                            (
-                                remote_player_query.single_mut(),
-                                local_player_query.single_mut(),
-                            )
-                        };
-
-                        if matches!(*game_state, GameState::FinalClash) {
-                            if received_input.attacking.is_valid()
-                                && current_player.final_clash_last_swing.is_none()
-                            {
-                                current_player.final_clash_last_swing =
-                                    Some(received_input.attacking);
-                            }
-                        } else {
-                            let mut stamina_loss = Unorm64(0);
-                            if received_input.attacking.is_valid() {
-                                let swing_result = current_player.swing(
-                                    &mut other_player,
-                                    received_input.attacking,
-                                    TEST_ATTACK,
-                                );
-                                if let Some(swing_result) = swing_result {
-                                    // current_player_animation.next.push_back(
-                                    //     AnimationQueueItem::Immediate(
-                                    //         animation_library.counter_attack.clone(),
-                                    //     ),
-                                    // );
-
-                                    let block_quality =
-                                        1.0 - (swing_result.abs() as f32).clamp(0.0, 1.0);
-                                    let sound_block_quality = block_quality.powf(16.0);
-                                    audio.play_with_settings(
-                                        audio_library.block.clone(),
-                                        PlaybackSettings {
-                                            repeat: false,
-                                            volume: (1.0 - sound_block_quality) * VOLUME_SCALE,
-                                            speed: 1.0,
-                                        },
-                                    );
-                                    audio.play_with_settings(
-                                        audio_library.perfect_block.clone(),
-                                        PlaybackSettings {
-                                            repeat: false,
-                                            volume: sound_block_quality * VOLUME_SCALE,
-                                            speed: 1.0,
-                                        },
-                                    );
-
-                                    let is_local = handle == 0;
-                                    if block_quality == 1.0 {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("INHUMAN BLOCK".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 0.01);
-                                    } else if block_quality > 0.999 {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("Perfect Block".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 0.1);
-                                    } else if block_quality > 0.99 {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("Excellent Block".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 0.25);
-                                    } else if block_quality > 0.9 {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("Good Block".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 0.5);
-                                    } else if block_quality > 0.8 {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("Decent Block".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 1.0);
-                                    } else {
-                                        if is_local {
-                                            ev_block.send(BlockEvent("Sloppy Block".into()));
-                                        }
-                                        // stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 1.2);
-                                    }
-                                    stamina_loss = Unorm64::from_f64(
-                                        BASE_STAMINA_LOSS
-                                            * (1.0 - (block_quality * 0.8)).powf(1.0) as f64,
-                                    )
-                                } else {
-                                    // current_player_animation.next.push_back(
-                                    //     AnimationQueueItem::Immediate(
-                                    //         animation_library.attack.clone(),
-                                    //     ),
-                                    // );
-                                }
-                                // current_player_animation.start = FrameOffset::now(&last_tick_time);
-                                // current_player_animation.next.push_back(
-                                //     AnimationQueueItem::UponCompletion(
-                                //         animation_library.idle.clone(),
-                                //     ),
-                                // );
-                            } else {
-                                if let Some(current_attack) = &other_player.current_attack {
-                                    if FrameOffset::now(&last_tick_time)
-                                        > other_player.attack_start_time
-                                            + current_attack.startup_time
-                                            + current_attack.block_grace
-                                    {
-                                        stamina_loss = Unorm64::from_f64(BASE_STAMINA_LOSS * 1.5);
-                                        other_player.current_attack = None;
-                                    }
-                                }
-                            }
-                            if stamina_loss < current_player.stamina {
-                                current_player.stamina.0 -= stamina_loss.0;
-                            } else {
-                                if stamina_loss.0 > 0 && current_player.stamina == Unorm64(0) {
-                                    ev_game.send(GameEvent::GameOver {
-                                        loser: Some(handle),
-                                    });
-                                } else {
-                                    current_player.stamina = Unorm64(0);
-                                }
-                            }
-                        }
+                    if let Some(sink) = audio_sinks.get(sink) {
+                        sink.stop();
                     }
-                    let (
-                        mut local_player,
-                        mut remote_player,
-                    ) = {
-                        (
-                            local_player_query.single_mut(),
-                            remote_player_query.single_mut(),
-                        )
-                    };
-                    if matches!(*game_state, GameState::FinalClash) {
-                        if let Some(next_clash) = final_clash.next_clash {
-                            if FrameOffset::now(&last_tick_time)
-                                .get_offset_seconds(&final_clash.next_clash.unwrap())
-                                < -CLASH_LENGTH
-                            {
-                                println!("next clash");
-                                if local_player.final_clash_last_swing.is_none() {
-                                    local_player.take_final_clash_life();
-                                    if remote_player.final_clash_last_swing.is_some() {
-                                        audio.play_with_settings(
-                                            audio_library.flesh_cut.clone(),
-                                            PlaybackSettings {
-                                                repeat: false,
-                                                volume: 1.0 * VOLUME_SCALE,
-                                                speed: 1.0,
-                                            },
-                                        );
-                                    }
-                                }
-                                if remote_player.final_clash_last_swing.is_none() {
-                                    remote_player.take_final_clash_life();
-                                    if local_player.final_clash_last_swing.is_some() {
-                                        audio.play_with_settings(
-                                            audio_library.flesh_cut.clone(),
-                                            PlaybackSettings {
-                                                repeat: false,
-                                                volume: 1.0 * VOLUME_SCALE,
-                                                speed: 1.0,
-                                            },
-                                        );
-                                    }
-                                }
-
-                                final_clash.next_clash = None;
-                                local_player.final_clash_last_swing = None;
-                                remote_player.final_clash_last_swing = None;
-                            } else {
-                                match (
-                                    local_player.final_clash_last_swing,
-                                    remote_player.final_clash_last_swing,
-                                ) {
-                                    (Some(local_clash), Some(remote_clash)) => {
-                                        let local_offset =
-                                            local_clash.get_offset_seconds(&next_clash);
-                                        let remote_offset =
-                                            remote_clash.get_offset_seconds(&next_clash);
-                                        if local_offset.0.abs() < remote_offset.0.abs() {
-                                            remote_player.take_final_clash_life()
-                                        }
-                                        if remote_offset.0.abs() < local_offset.0.abs() {
-                                            local_player.take_final_clash_life()
-                                        }
-
-                                        audio.play_with_settings(
-                                            audio_library.block.clone(),
-                                            PlaybackSettings {
-                                                repeat: false,
-                                                volume: 1.0 * VOLUME_SCALE,
-                                                speed: 1.0,
-                                            },
-                                        );
-
-                                        final_clash.next_clash = None;
-                                        local_player.final_clash_last_swing = None;
-                                        remote_player.final_clash_last_swing = None;
-                                    }
-                                    (_, _) => {}
-                                }
-                            }
-
-                            let local_dead = local_player.final_clash_lives == 0;
-                            let remote_dead = remote_player.final_clash_lives == 0;
-                            match (local_dead, remote_dead) {
-                                (true, true) => ev_game.send(GameEvent::GameOver { loser: None }),
-                                (true, false) => {
-                                    ev_game.send(GameEvent::GameOver { loser: Some(1) })
-                                }
-                                (false, true) => {
-                                    ev_game.send(GameEvent::GameOver { loser: Some(0) })
-                                }
-                                (false, false) => {}
-                            }
-                        }
-                    } else {
-                        if local_player.stamina == Unorm64(0) && remote_player.stamina == Unorm64(0)
-                        {
-                            println!("Beginning final clash");
-                            *game_state = GameState::FinalClash;
-                        }
+                    false
+                });
+                match effect.event {
+                    SimEvent::Block { handle, .. } => ev_block.send(BlockEvent::Cancelled {
+                        frame: effect.frame,
+                        handle,
+                    }),
+                    SimEvent::GameOver { .. } | SimEvent::Forfeit { .. } => {
+                        ev_game.send(GameEvent::GameOverCancelled)
                     }
+                    _ => {}
                 }
             }
         }
     }
 }
+
+/// Lets go of sounds whose effects can no longer be cancelled, so they play out detached.
+fn release_confirmed_sounds(
+    mut effect_sounds: ResMut<EffectSounds>,
+    effect_ledger: Res<EffectLedger>,
+) {
+    effect_sounds
+        .0
+        .retain(|(effect, _)| !effect_ledger.is_confirmed(effect.frame));
+}
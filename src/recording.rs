@@ -0,0 +1,109 @@
+//! Writing matches to replay files and reading them back.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+
+use crate::sim::{
+    format::{self, FormatError},
+    replay::{Replay, Rules},
+    FrameRate, SendInput,
+};
+
+/// Collects the inputs of every frame as it is simulated, and who had left by then, and
+/// keeps those of confirmed frames in a [`Replay`] that is written to `path`.
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    path: PathBuf,
+    replay: Replay,
+    /// The latest inputs of frames that may still be resimulated, and which players had
+    /// left.
+    unconfirmed: BTreeMap<usize, ([SendInput; 2], [bool; 2])>,
+    unsaved: bool,
+}
+
+impl ReplayRecorder {
+    pub fn new(path: PathBuf, frame_rate: FrameRate) -> Self {
+        Self {
+            path,
+            replay: Replay::new(frame_rate),
+            unconfirmed: BTreeMap::new(),
+            unsaved: false,
+        }
+    }
+    /// Records the inputs `frame` was (re)simulated with, and which players had left.
+    pub fn record(&mut self, frame: usize, inputs: [SendInput; 2], disconnected: [bool; 2]) {
+        self.unconfirmed.insert(frame, (inputs, disconnected));
+    }
+    /// Moves every frame up to and including `frame` into the replay.
+    pub fn confirm(&mut self, frame: usize) {
+        let still_unconfirmed = self.unconfirmed.split_off(&(frame + 1));
+        for (frame, (inputs, disconnected)) in
+            std::mem::replace(&mut self.unconfirmed, still_unconfirmed)
+        {
+            // Frames before the first one recorded, such as those a spectator skipped, were
+            // confirmed already.
+            if frame != self.replay.inputs.len() + 1 {
+                continue;
+            }
+            self.replay.inputs.push(inputs);
+            for handle in (0..2).filter(|&handle| disconnected[handle]) {
+                if !self.replay.has_left(frame, handle) {
+                    self.replay.departures.push((frame, handle));
+                }
+            }
+            self.unsaved = true;
+        }
+    }
+    /// Writes the replay if anything was confirmed since it was last written.
+    pub fn save(&mut self) -> io::Result<()> {
+        if self.unsaved {
+            fs::write(&self.path, format::to_binary(&self.replay))?;
+            self.unsaved = false;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Format(FormatError),
+    /// The replay was recorded under rules other than [`Rules::current`].
+    DifferentRules(Box<Rules>),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "could not read replay: {err}"),
+            ReplayError::Format(err) => write!(f, "could not read replay: {err}"),
+            ReplayError::DifferentRules(rules) => write!(
+                f,
+                "the replay was recorded with different rules, {rules:?}, than this build's {:?}",
+                Rules::current(rules.frame_rate().unwrap_or_default())
+            ),
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+pub fn load_replay(path: &Path) -> Result<Replay, ReplayError> {
+    let bytes = fs::read(path).map_err(ReplayError::Io)?;
+    let replay: Replay = format::from_binary(&bytes).map_err(ReplayError::Format)?;
+    // The frame rate is whatever the match was played at, and the replay is played back at
+    // the same one.
+    match replay.rules.frame_rate() {
+        Some(frame_rate) if replay.rules == Rules::current(frame_rate) => Ok(replay),
+        _ => Err(ReplayError::DifferentRules(Box::new(replay.rules))),
+    }
+}
+
+#[cfg(test)]
+mod tests;
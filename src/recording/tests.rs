@@ -0,0 +1,117 @@
+//! A match recorded through a rollback and a departure, written out and played back.
+
+use std::{env, fs, path::PathBuf, process};
+
+use counter_attack::sim::{
+    self, simulate, FrameOffset, FrameRate, GameState, Second, WorldSnapshot,
+};
+
+use super::*;
+
+/// Player 1 is gone from this frame on.
+const LEFT_FRAME: usize = 50;
+const FRAMES: usize = 60;
+/// How many frames behind the latest the recorder is confirmed up to.
+const CONFIRM_DELAY: usize = 8;
+
+/// A file in the temporary directory for this test process alone.
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("counter-attack-{name}-{}.replay", process::id()))
+}
+
+/// Simulates the frame after `world` the way the game does: players who have left don't
+/// swing, and forfeit once the frame is done. Returns the inputs it was simulated with.
+fn advance(
+    world: &mut WorldSnapshot,
+    inputs: [SendInput; 2],
+    disconnected: [bool; 2],
+) -> [SendInput; 2] {
+    let inputs = [0, 1].map(|handle| match disconnected[handle] {
+        true => world.players[handle].idle_input(),
+        false => inputs[handle],
+    });
+    simulate(world, inputs);
+    for handle in (0..2).filter(|&handle| disconnected[handle]) {
+        sim::forfeit(world, handle);
+    }
+    inputs
+}
+
+/// Plays a match in which player 0 attacks, player 1 blocks and then leaves, recording
+/// it into `recorder`. Player 1's block is first predicted not to happen and rolled back
+/// once it arrives.
+fn play_match(recorder: &mut ReplayRecorder) -> WorldSnapshot {
+    let mut world = WorldSnapshot::default();
+    while world.frame < FRAMES {
+        if world.frame == 20 {
+            let mut predicted = world.clone();
+            for _ in 0..5 {
+                let inputs = predicted.players.clone().map(|player| player.idle_input());
+                let inputs = advance(&mut predicted, inputs, [false; 2]);
+                recorder.record(predicted.frame, inputs, [false; 2]);
+            }
+        }
+
+        let mut inputs = world.players.clone().map(|player| player.idle_input());
+        let swinging = match world.frame {
+            0 => Some(0),
+            20 => Some(1),
+            _ => None,
+        };
+        if let Some(handle) = swinging {
+            let frame = world.frame;
+            inputs[handle] = inputs[handle].swung(FrameOffset {
+                frame,
+                offset: 1234,
+            });
+        }
+        let disconnected = [false, world.frame + 1 >= LEFT_FRAME];
+        let inputs = advance(&mut world, inputs, disconnected);
+        recorder.record(world.frame, inputs, disconnected);
+        recorder.confirm(world.frame.saturating_sub(CONFIRM_DELAY));
+    }
+    recorder.confirm(world.frame);
+    world
+}
+
+#[test]
+fn a_recorded_match_plays_back_to_the_same_snapshot() {
+    let path = temp_path("playback");
+    let mut recorder = ReplayRecorder::new(path.clone(), FrameRate::default());
+    let live = play_match(&mut recorder);
+    assert_eq!(live.game_state, GameState::Over);
+    recorder.save().unwrap();
+    let replay = load_replay(&path);
+    fs::remove_file(&path).unwrap();
+    let replay = replay.unwrap();
+
+    assert_eq!(replay.inputs.len(), FRAMES);
+    assert_eq!(replay.departures, [(LEFT_FRAME, 1)]);
+    let mut world = WorldSnapshot::new(replay.rules.frame_rate().unwrap());
+    while world.frame < replay.inputs.len() {
+        let frame = world.frame + 1;
+        let inputs = [0, 1].map(|handle| replay.input(frame, handle));
+        let disconnected = [0, 1].map(|handle| replay.has_left(frame, handle));
+        advance(&mut world, inputs, disconnected);
+    }
+    assert_eq!(world.checksum(), live.checksum());
+}
+
+#[test]
+fn a_replay_recorded_under_other_rules_is_refused() {
+    let path = temp_path("other-rules");
+    let mut other_lives = Replay::new(FrameRate::default());
+    other_lives.rules.final_clash_lives += 1;
+    let mut unsupported_rate = Replay::new(FrameRate::default());
+    unsupported_rate.rules.frametime = Second(7);
+
+    for replay in [other_lives, unsupported_rate] {
+        fs::write(&path, format::to_binary(&replay)).unwrap();
+        let result = load_replay(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(ReplayError::DifferentRules(rules)) => assert_eq!(*rules, replay.rules),
+            result => panic!("expected different rules, got {result:?}"),
+        }
+    }
+}
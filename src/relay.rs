@@ -0,0 +1,98 @@
+//! Forwarding datagrams between two players who can't reach each other directly.
+//!
+//! Both players send everything meant for the other to the relay server, prefixed with
+//! [`TAG`] and a session ID they agreed on. The first two addresses to use a session ID
+//! are paired, and the relay passes each datagram unchanged to the other one, which
+//! strips the prefix again. A player who restarts comes back from a new address, and takes
+//! their old place once that has been quiet for a while.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Starts every relayed datagram.
+pub const TAG: &[u8; 4] = b"RLAY";
+/// The tag and the little-endian session ID.
+pub const HEADER_LENGTH: usize = TAG.len() + 8;
+/// A session nobody has sent anything through for this long is forgotten.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// A player who hasn't sent anything for this long gives up their place to a new address.
+/// GGRS sends something every frame while a match is running.
+const PLAYER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `payload` addressed to the other player in session `session_id`.
+pub fn wrap(session_id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    datagram.extend_from_slice(TAG);
+    datagram.extend_from_slice(&session_id.to_le_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// The session ID and payload of a relayed datagram.
+pub fn unwrap(datagram: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = datagram.strip_prefix(TAG)?;
+    let (session_id, payload) = rest.split_first_chunk::<8>()?;
+    Some((u64::from_le_bytes(*session_id), payload))
+}
+
+struct Seat {
+    addr: SocketAddr,
+    last_heard: Instant,
+}
+
+struct RelaySession {
+    players: [Option<Seat>; 2],
+}
+
+/// The relay server's sessions, separate from its socket.
+#[derive(Default)]
+pub struct Relay {
+    sessions: HashMap<u64, RelaySession>,
+}
+
+impl Relay {
+    /// Where to forward `datagram`, received from `from`, if anywhere. A session's first
+    /// two senders are its players; anyone else is ignored, unless a player has gone quiet.
+    pub fn route(&mut self, from: SocketAddr, datagram: &[u8], now: Instant) -> Option<SocketAddr> {
+        let (session_id, _) = unwrap(datagram)?;
+        let session = self
+            .sessions
+            .entry(session_id)
+            .or_insert_with(|| RelaySession {
+                players: [None, None],
+            });
+        let seated = |seat: &Option<Seat>| seat.as_ref().is_some_and(|seat| seat.addr == from);
+        let stale = |seat: &Option<Seat>| {
+            seat.as_ref()
+                .is_some_and(|seat| now - seat.last_heard >= PLAYER_TIMEOUT)
+        };
+        let players = &session.players;
+        let index = players
+            .iter()
+            .position(seated)
+            .or_else(|| players.iter().position(Option::is_none))
+            .or_else(|| players.iter().position(stale))?;
+        session.players[index] = Some(Seat {
+            addr: from,
+            last_heard: now,
+        });
+        session.players[1 - index].as_ref().map(|seat| seat.addr)
+    }
+
+    /// Forgets sessions that have gone quiet.
+    pub fn expire(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| {
+            session
+                .players
+                .iter()
+                .flatten()
+                .any(|seat| now - seat.last_heard < IDLE_TIMEOUT)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,97 @@
+//! The relay's sessions, driven with datagrams from made-up addresses at made-up times.
+
+use super::*;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+const SESSION: u64 = 42;
+
+#[test]
+fn payloads_survive_wrapping() {
+    let datagram = wrap(SESSION, b"payload");
+    assert_eq!(datagram.len(), HEADER_LENGTH + b"payload".len());
+    assert_eq!(unwrap(&datagram), Some((SESSION, &b"payload"[..])));
+    assert_eq!(unwrap(&wrap(SESSION, b"")), Some((SESSION, &b""[..])));
+}
+
+#[test]
+fn only_relay_datagrams_unwrap() {
+    assert_eq!(unwrap(b"GGRS message"), None);
+    assert_eq!(unwrap(b"RLAY1234"), None);
+}
+
+#[test]
+fn the_first_two_senders_are_paired() {
+    let mut relay = Relay::default();
+    let now = Instant::now();
+    let datagram = wrap(SESSION, b"payload");
+
+    assert_eq!(relay.route(addr(7000), &datagram, now), None);
+    assert_eq!(relay.route(addr(7001), &datagram, now), Some(addr(7000)));
+    assert_eq!(relay.route(addr(7000), &datagram, now), Some(addr(7001)));
+}
+
+#[test]
+fn a_third_sender_is_ignored() {
+    let mut relay = Relay::default();
+    let now = Instant::now();
+    let datagram = wrap(SESSION, b"payload");
+    relay.route(addr(7000), &datagram, now);
+    relay.route(addr(7001), &datagram, now);
+
+    assert_eq!(relay.route(addr(7002), &datagram, now), None);
+    assert_eq!(relay.route(addr(7000), &datagram, now), Some(addr(7001)));
+    assert_eq!(relay.route(addr(7001), &datagram, now), Some(addr(7000)));
+}
+
+#[test]
+fn sessions_are_kept_apart() {
+    let mut relay = Relay::default();
+    let now = Instant::now();
+    relay.route(addr(7000), &wrap(SESSION, b"payload"), now);
+    relay.route(addr(7001), &wrap(SESSION, b"payload"), now);
+
+    let other = wrap(SESSION + 1, b"payload");
+    assert_eq!(relay.route(addr(7002), &other, now), None);
+    assert_eq!(relay.route(addr(7003), &other, now), Some(addr(7002)));
+    assert_eq!(relay.route(addr(7000), b"GGRS message", now), None);
+}
+
+#[test]
+fn a_restarted_player_takes_their_old_place() {
+    let mut relay = Relay::default();
+    let now = Instant::now();
+    let datagram = wrap(SESSION, b"payload");
+    relay.route(addr(7000), &datagram, now);
+    relay.route(addr(7001), &datagram, now);
+
+    // The other player keeps sending, so the session stays alive.
+    let later = now + PLAYER_TIMEOUT;
+    assert_eq!(relay.route(addr(7001), &datagram, later), Some(addr(7000)));
+    assert_eq!(relay.route(addr(7002), &datagram, later), Some(addr(7001)));
+    assert_eq!(relay.route(addr(7001), &datagram, later), Some(addr(7002)));
+}
+
+#[test]
+fn a_quiet_session_is_forgotten() {
+    let mut relay = Relay::default();
+    let now = Instant::now();
+    let datagram = wrap(SESSION, b"payload");
+    relay.route(addr(7000), &datagram, now);
+    relay.route(addr(7001), &datagram, now + IDLE_TIMEOUT / 2);
+
+    relay.expire(now + IDLE_TIMEOUT);
+    assert_eq!(
+        relay.route(addr(7000), &datagram, now + IDLE_TIMEOUT),
+        Some(addr(7001))
+    );
+    let much_later = now + 3 * IDLE_TIMEOUT;
+    relay.expire(much_later);
+    assert_eq!(relay.route(addr(7002), &datagram, much_later), None);
+    assert_eq!(
+        relay.route(addr(7003), &datagram, much_later),
+        Some(addr(7002))
+    );
+}
@@ -0,0 +1,706 @@
+//! The GGRS sessions the game can run on top of.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    slice,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use counter_attack::{
+    lobby::{self, LobbyReply, LobbyRequest},
+    sim::{format::FormatError, replay::Rules, FrameRate, SendInput, WorldSnapshot},
+};
+use ggrs::{
+    Config, DesyncDetection, Frame, GGRSError, GGRSEvent, GGRSRequest, NetworkStats, P2PSession,
+    PlayerHandle, PlayerType, SessionBuilder, SessionState, SpectatorSession, SyncTestSession,
+};
+
+use crate::{
+    auth::{AuthTransport, RejectedPackets, SessionKey},
+    conditioner::{ConditionedTransport, Conditions},
+    desync::DesyncDetector,
+    socket::{bind_udp, GameSocket, RelayTransport, Transport},
+};
+
+#[derive(Debug)]
+pub struct GGRSConfig;
+impl Config for GGRSConfig {
+    type Input = SendInput;
+    type State = WorldSnapshot;
+    type Address = SocketAddr;
+}
+
+/// Something the session reported, passed on to the rest of the game.
+pub struct SessionEvent(pub GGRSEvent<GGRSConfig>);
+
+/// Frames of input delay GGRS is allowed to add, as an upper bound on `auto`.
+pub const MAX_INPUT_DELAY: usize = 8;
+/// How far ahead a sync test predicts, which its check distance has to stay below.
+pub const SYNCTEST_MAX_PREDICTION: usize = 8;
+/// How many round trips to the other player `auto` input delay is chosen from.
+const PROBE_ROUND_TRIPS: usize = 5;
+/// How often pings, and this player's rules, are sent until answered.
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputDelay {
+    Frames(usize),
+    /// Enough frames to cover the time input takes to reach the other player, measured
+    /// before the session starts.
+    Auto,
+}
+
+/// How long the other side can go quiet before the game notices.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// When to report the connection as interrupted.
+    pub disconnect_notify_start: Duration,
+    /// When to give up on the other side altogether.
+    pub disconnect_timeout: Duration,
+}
+
+/// How the connection to the other player is tuned, however they were found.
+#[derive(Clone, Copy, Debug)]
+pub struct NetSettings {
+    /// Both players have to simulate at the same rate.
+    pub frame_rate: FrameRate,
+    /// Frames to hold local input back for, trading latency for fewer rollbacks.
+    pub input_delay: InputDelay,
+    /// How many frames ahead of the other player's last input to predict before waiting.
+    pub max_prediction: usize,
+    /// Compare checksums with the other player every this many confirmed frames.
+    pub checksum_interval: usize,
+    pub timeouts: Timeouts,
+    /// Sign and check every datagram exchanged with the other player with this.
+    pub key: Option<SessionKey>,
+    /// Make the connection worse on purpose, for testing.
+    pub conditions: Option<Conditions>,
+}
+
+/// How to connect to the other player.
+pub struct P2PSettings {
+    /// The host plays as handle 0 and whoever joins as handle 1, so both peers agree on
+    /// the order of every input and snapshot.
+    pub local_handle: PlayerHandle,
+    pub local_port: u16,
+    pub remote_addr: SocketAddr,
+    /// Where to send the confirmed inputs on to.
+    pub spectators: Vec<SocketAddr>,
+    /// Reach the other player through the relay server at `remote_addr`, as the session
+    /// with this ID.
+    pub relay_session: Option<u64>,
+    pub net: NetSettings,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Bind { port: u16, err: io::Error },
+    Ggrs(GGRSError),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Bind { port, err } if err.kind() == ErrorKind::AddrInUse => write!(
+                f,
+                "UDP port {port} is already in use, perhaps by another copy of the game; \
+                 choose another with --port"
+            ),
+            SessionError::Bind { port, err } => {
+                write!(f, "could not listen on UDP port {port}: {err}")
+            }
+            SessionError::Ggrs(err) => write!(f, "could not start the session: {err}"),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl From<GGRSError> for SessionError {
+    fn from(err: GGRSError) -> Self {
+        SessionError::Ggrs(err)
+    }
+}
+
+fn bind(port: u16, peer_addr: SocketAddr) -> Result<UdpSocket, SessionError> {
+    bind_udp(port, peer_addr).map_err(|err| SessionError::Bind { port, err })
+}
+
+/// A socket over `udp`, under `conditions` and through the relay server and session in
+/// `relay` if given, signing with `key` if there is one, except to and from
+/// `unsigned_peer`.
+fn game_socket(
+    udp: UdpSocket,
+    conditions: Option<Conditions>,
+    relay: Option<(SocketAddr, u64)>,
+    key: Option<SessionKey>,
+    unsigned_peer: Option<SocketAddr>,
+) -> GameSocket {
+    let mut transport: Box<dyn Transport> = Box::new(udp);
+    let mut rejected_packets = Arc::default();
+    if let Some(conditions) = conditions {
+        transport = Box::new(ConditionedTransport::new(transport, conditions));
+    }
+    if let Some((relay_addr, session_id)) = relay {
+        transport = Box::new(RelayTransport::new(transport, relay_addr, session_id));
+    }
+    if let Some(key) = key {
+        let mut auth = AuthTransport::new(transport, key);
+        if let Some(addr) = unsigned_peer {
+            auth = auth.with_unsigned_peer(addr);
+        }
+        rejected_packets = auth.rejected_packets();
+        transport = Box::new(auth);
+    }
+    GameSocket::new(transport).with_rejected_packets(rejected_packets)
+}
+
+/// Checks that the other player plays by the same rules, at the same frame rate, before a
+/// P2P session starts, and measures the round trip to them to choose the input delay it
+/// starts with unless one was given. GGRS can't change either once started.
+pub struct LatencyProbe {
+    builder: SessionBuilder<GGRSConfig>,
+    socket: GameSocket,
+    local_handle: PlayerHandle,
+    remote_addr: SocketAddr,
+    frame_rate: FrameRate,
+    checksum_interval: usize,
+    /// Given, or chosen once enough pings have been answered.
+    input_delay: Option<usize>,
+    /// When this player's rules were last sent, until the other player's arrive.
+    rules_sent: Option<Instant>,
+    /// Set once the other player's rules have arrived and match this player's.
+    rules_agreed: bool,
+    /// Set once the other player's rules have turned out to differ.
+    refused: bool,
+    events: Vec<GGRSEvent<GGRSConfig>>,
+    /// When each ping was sent, indexed by its nonce.
+    pings: Vec<Instant>,
+    round_trips: Vec<Duration>,
+}
+
+impl LatencyProbe {
+    /// Sends this player's rules and the next ping once due, and returns the input delay
+    /// to start with once the other player's rules agree and enough pings have been
+    /// answered. Anything else that arrives meanwhile is kept for the session.
+    fn poll(&mut self) -> Option<usize> {
+        let rules = Rules::current(self.frame_rate);
+        for (addr, peer_rules) in self.socket.receive_rules() {
+            if addr != self.remote_addr || self.refused {
+                continue;
+            }
+            match peer_rules {
+                Ok(peer_rules) if peer_rules == rules => self.rules_agreed = true,
+                peer_rules => self.refuse(peer_rules, &rules),
+            }
+        }
+        if self.refused {
+            return None;
+        }
+        if !self.rules_agreed
+            && self
+                .rules_sent
+                .is_none_or(|sent| sent.elapsed() >= PING_INTERVAL)
+        {
+            self.socket.send_rules(self.remote_addr);
+            self.rules_sent = Some(Instant::now());
+        }
+        if self.input_delay.is_none() {
+            self.input_delay = self.measure_input_delay();
+        }
+        self.input_delay.filter(|_| self.rules_agreed)
+    }
+
+    /// Shows the other player as gone, as the session can't start.
+    fn refuse(&mut self, peer_rules: Result<Rules, FormatError>, rules: &Rules) {
+        let remote_addr = self.remote_addr;
+        match peer_rules {
+            Ok(peer_rules) => match peer_rules.frame_rate() {
+                Some(frame_rate) if frame_rate != self.frame_rate => println!(
+                    "{remote_addr} plays at {} fps, but this player at {} fps; both players \
+                     need the same --fps",
+                    frame_rate.fps(),
+                    self.frame_rate.fps()
+                ),
+                _ => println!(
+                    "{remote_addr} plays by different rules, {peer_rules:?}, than this build's \
+                     {rules:?}; both players need the same version of the game"
+                ),
+            },
+            Err(err) => println!(
+                "Could not read the rules {remote_addr} plays by, so both players need the \
+                 same version of the game: {err}"
+            ),
+        }
+        self.refused = true;
+        self.events
+            .push(GGRSEvent::Disconnected { addr: remote_addr });
+    }
+
+    /// Sends the next ping once one is due, and returns the input delay to use once enough
+    /// have been answered.
+    fn measure_input_delay(&mut self) -> Option<usize> {
+        for (addr, nonce) in self.socket.receive_pongs() {
+            if let Some(sent) = self
+                .pings
+                .get(nonce as usize)
+                .filter(|_| addr == self.remote_addr)
+            {
+                self.round_trips.push(sent.elapsed());
+            }
+        }
+        if self.round_trips.len() >= PROBE_ROUND_TRIPS {
+            self.round_trips.sort();
+            let round_trip = self.round_trips[self.round_trips.len() / 2];
+            let input_delay = auto_input_delay(round_trip, self.frame_rate);
+            println!(
+                "Round trip to {} is {round_trip:?}, using an input delay of {input_delay} frames",
+                self.remote_addr
+            );
+            return Some(input_delay);
+        }
+        if self
+            .pings
+            .last()
+            .is_none_or(|sent| sent.elapsed() >= PING_INTERVAL)
+        {
+            self.socket.ping(self.remote_addr, self.pings.len() as u64);
+            self.pings.push(Instant::now());
+        }
+        None
+    }
+
+    fn start(self, input_delay: usize) -> Session {
+        let desyncs = DesyncDetector::new(
+            self.socket.checksums(),
+            self.remote_addr,
+            self.checksum_interval,
+        );
+        let rejected_packets = self.socket.rejected_packets();
+        let session = self
+            .builder
+            .with_input_delay(input_delay)
+            .start_p2p_session(self.socket)
+            .expect("the players were all added before probing");
+        Session::P2P {
+            session,
+            local_handle: self.local_handle,
+            remote_addr: self.remote_addr,
+            input_delay,
+            desyncs,
+            rejected_packets,
+        }
+    }
+}
+
+/// Waits for a lobby server to introduce the other player.
+pub struct Rendezvous {
+    socket: GameSocket,
+    local_port: u16,
+    lobby_addr: SocketAddr,
+    room: String,
+    net: NetSettings,
+    last_join: Option<Instant>,
+    /// Set once the lobby has turned this player away.
+    refused: bool,
+    events: Vec<GGRSEvent<GGRSConfig>>,
+}
+
+impl Rendezvous {
+    /// Resends the join once due, and returns this player's handle and the other player's
+    /// address once matched.
+    fn poll(&mut self) -> Option<(PlayerHandle, SocketAddr)> {
+        for (addr, reply) in self.socket.receive_lobby_replies() {
+            if addr != self.lobby_addr || self.refused {
+                continue;
+            }
+            match reply {
+                LobbyReply::Waiting => {}
+                LobbyReply::Matched { peer, handle } if handle < 2 => return Some((handle, peer)),
+                LobbyReply::Matched { .. } => {}
+                LobbyReply::RoomFull => {
+                    println!("Room {:?} already has two players in it", self.room);
+                    // Show the lobby as gone, as there is nothing more to do.
+                    self.refused = true;
+                    self.events.push(GGRSEvent::Disconnected {
+                        addr: self.lobby_addr,
+                    });
+                }
+            }
+        }
+        if !self.refused
+            && self
+                .last_join
+                .is_none_or(|last_join| last_join.elapsed() >= lobby::JOIN_INTERVAL)
+        {
+            let request = LobbyRequest::Join {
+                room: self.room.clone(),
+            };
+            self.socket.send_to_lobby(self.lobby_addr, &request);
+            self.last_join = Some(Instant::now());
+        }
+        None
+    }
+
+    fn start(self, local_handle: PlayerHandle, remote_addr: SocketAddr) -> Session {
+        println!("Matched with {remote_addr}");
+        let settings = P2PSettings {
+            local_handle,
+            local_port: self.local_port,
+            remote_addr,
+            spectators: vec![],
+            relay_session: None,
+            net: self.net,
+        };
+        Session::p2p_on(settings, self.socket).expect("the lobby only hands out valid handles")
+    }
+}
+
+/// Enough frames of delay for input to arrive before it's needed, if the round trip is
+/// split evenly between the two directions.
+fn auto_input_delay(round_trip: Duration, frame_rate: FrameRate) -> usize {
+    let one_way = round_trip.as_secs_f64() / 2.0;
+    let frames = (one_way / frame_rate.frametime().as_secs_f64()).ceil() as usize;
+    frames.min(MAX_INPUT_DELAY)
+}
+
+/// There is only ever one session, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Resource)]
+pub enum Session {
+    P2P {
+        session: P2PSession<GGRSConfig>,
+        local_handle: PlayerHandle,
+        remote_addr: SocketAddr,
+        input_delay: usize,
+        desyncs: DesyncDetector,
+        rejected_packets: Arc<Mutex<RejectedPackets>>,
+    },
+    /// Rolls back and resimulates every frame, checking the checksums match.
+    SyncTest {
+        session: SyncTestSession<GGRSConfig>,
+        check_distance: usize,
+    },
+    /// Waiting for a [`Rendezvous`] before becoming [`Session::Probing`].
+    Rendezvous(Rendezvous),
+    /// Waiting for a [`LatencyProbe`] before becoming [`Session::P2P`].
+    Probing(LatencyProbe),
+    /// Watches a match hosted by one of the players, without a local player of its own.
+    Spectator {
+        session: SpectatorSession<GGRSConfig>,
+        host_addr: SocketAddr,
+        rejected_packets: Arc<Mutex<RejectedPackets>>,
+    },
+    /// Both players on one machine. A sync test with nothing to check just passes their
+    /// inputs through.
+    Local(SyncTestSession<GGRSConfig>),
+}
+
+impl Session {
+    pub fn p2p(settings: P2PSettings) -> Result<Self, SessionError> {
+        let udp = bind(settings.local_port, settings.remote_addr)?;
+        let relay = settings
+            .relay_session
+            .map(|session_id| (settings.remote_addr, session_id));
+        let net = settings.net;
+        let socket = game_socket(udp, net.conditions, relay, net.key, None);
+        Self::p2p_on(settings, socket)
+    }
+    fn p2p_on(settings: P2PSettings, socket: GameSocket) -> Result<Self, SessionError> {
+        let net = settings.net;
+        let mut session_builder = SessionBuilder::<GGRSConfig>::new()
+            .with_fps(net.frame_rate.fps())?
+            .with_max_prediction_window(net.max_prediction)
+            .with_disconnect_timeout(net.timeouts.disconnect_timeout)
+            .with_disconnect_notify_delay(net.timeouts.disconnect_notify_start)
+            // It reports predicted frames as desynced; `DesyncDetector` checks instead.
+            .with_desync_detection_mode(DesyncDetection::Off)
+            .add_player(PlayerType::Local, settings.local_handle)?
+            .add_player(
+                PlayerType::Remote(settings.remote_addr),
+                1 - settings.local_handle,
+            )?;
+        // Spectator handles come after the players'.
+        for (handle, &addr) in (2..).zip(&settings.spectators) {
+            session_builder = session_builder.add_player(PlayerType::Spectator(addr), handle)?;
+        }
+
+        Ok(Session::Probing(LatencyProbe {
+            builder: session_builder,
+            socket: socket.with_rules(Rules::current(net.frame_rate)),
+            local_handle: settings.local_handle,
+            remote_addr: settings.remote_addr,
+            frame_rate: net.frame_rate,
+            checksum_interval: net.checksum_interval,
+            input_delay: match net.input_delay {
+                InputDelay::Frames(input_delay) => Some(input_delay),
+                InputDelay::Auto => None,
+            },
+            rules_sent: None,
+            rules_agreed: false,
+            refused: false,
+            events: vec![],
+            pings: vec![],
+            round_trips: vec![],
+        }))
+    }
+    /// Meets the other player in `room` on the lobby server at `lobby_addr`, before
+    /// connecting to them directly.
+    pub fn lobby(
+        local_port: u16,
+        lobby_addr: SocketAddr,
+        room: String,
+        net: NetSettings,
+    ) -> Result<Self, SessionError> {
+        Ok(Session::Rendezvous(Rendezvous {
+            // The lobby server doesn't know the key.
+            socket: game_socket(
+                bind(local_port, lobby_addr)?,
+                net.conditions,
+                None,
+                net.key,
+                Some(lobby_addr),
+            ),
+            local_port,
+            lobby_addr,
+            room,
+            net,
+            last_join: None,
+            refused: false,
+            events: vec![],
+        }))
+    }
+
+    pub fn synctest(check_distance: usize, frame_rate: FrameRate) -> Result<Self, SessionError> {
+        let session = SessionBuilder::<GGRSConfig>::new()
+            .with_fps(frame_rate.fps())?
+            .with_max_prediction_window(SYNCTEST_MAX_PREDICTION)
+            .with_check_distance(check_distance)
+            .start_synctest_session()?;
+        Ok(Session::SyncTest {
+            session,
+            check_distance,
+        })
+    }
+
+    pub fn spectator(
+        local_port: u16,
+        host_addr: SocketAddr,
+        timeouts: Timeouts,
+        key: Option<SessionKey>,
+        frame_rate: FrameRate,
+    ) -> Result<Self, SessionError> {
+        let socket = game_socket(bind(local_port, host_addr)?, None, None, key, None);
+        let rejected_packets = socket.rejected_packets();
+        let session = SessionBuilder::<GGRSConfig>::new()
+            .with_fps(frame_rate.fps())?
+            .with_disconnect_timeout(timeouts.disconnect_timeout)
+            .with_disconnect_notify_delay(timeouts.disconnect_notify_start)
+            // Catch up two frames at a time rather than staying behind the host forever.
+            .with_catchup_speed(2)?
+            .start_spectator_session(host_addr, socket);
+        Ok(Session::Spectator {
+            session,
+            host_addr,
+            rejected_packets,
+        })
+    }
+
+    pub fn local(frame_rate: FrameRate) -> Self {
+        let session = SessionBuilder::<GGRSConfig>::new()
+            .with_fps(frame_rate.fps())
+            .unwrap()
+            .with_check_distance(0)
+            .start_synctest_session()
+            .unwrap();
+        Session::Local(session)
+    }
+
+    /// The players whose input comes from this machine.
+    pub fn local_handles(&self) -> &[PlayerHandle] {
+        match self {
+            Session::P2P { local_handle, .. } => slice::from_ref(local_handle),
+            Session::Probing(probe) => slice::from_ref(&probe.local_handle),
+            Session::Rendezvous(_) | Session::Spectator { .. } => &[],
+            Session::SyncTest { .. } | Session::Local(_) => &[0, 1],
+        }
+    }
+
+    /// Whether the peers are synchronized and frames can be advanced.
+    pub fn current_state(&self) -> SessionState {
+        match self {
+            Session::P2P { session, .. } => session.current_state(),
+            Session::Spectator { session, .. } => session.current_state(),
+            Session::Rendezvous(_) | Session::Probing(_) => SessionState::Synchronizing,
+            Session::SyncTest { .. } | Session::Local(_) => SessionState::Running,
+        }
+    }
+
+    /// Whether `addr` is the other player, or the host when spectating, rather than a
+    /// spectator.
+    pub fn is_peer(&self, addr: SocketAddr) -> bool {
+        match self {
+            Session::P2P { remote_addr, .. } => addr == *remote_addr,
+            Session::Probing(probe) => addr == probe.remote_addr,
+            // Until matched, the lobby stands in for the other player.
+            Session::Rendezvous(rendezvous) => addr == rendezvous.lobby_addr,
+            Session::Spectator { host_addr, .. } => addr == *host_addr,
+            Session::SyncTest { .. } | Session::Local(_) => false,
+        }
+    }
+
+    /// The player shown as the local one, on the left of the screen. Without a single
+    /// local player, or until the lobby has said which one it is, that is handle 0.
+    pub fn viewpoint(&self) -> PlayerHandle {
+        match self {
+            Session::P2P { local_handle, .. } => *local_handle,
+            Session::Probing(probe) => probe.local_handle,
+            _ => 0,
+        }
+    }
+
+    /// Frames between taking local input and simulating it.
+    pub fn input_delay(&self) -> usize {
+        match self {
+            Session::P2P { input_delay, .. } => *input_delay,
+            _ => 0,
+        }
+    }
+    /// Adds the input of one of the [`Session::local_handles`].
+    pub fn add_local_input(
+        &mut self,
+        player_handle: PlayerHandle,
+        input: SendInput,
+    ) -> Result<(), GGRSError> {
+        match self {
+            Session::P2P { session, .. } => session.add_local_input(player_handle, input),
+            Session::SyncTest { session, .. } | Session::Local(session) => {
+                session.add_local_input(player_handle, input)
+            }
+            Session::Rendezvous(_) | Session::Probing(_) => Err(GGRSError::NotSynchronized),
+            Session::Spectator { .. } => Ok(()),
+        }
+    }
+    pub fn advance_frame(&mut self) -> Result<Vec<GGRSRequest<GGRSConfig>>, GGRSError> {
+        match self {
+            Session::P2P { session, .. } => session.advance_frame(),
+            Session::SyncTest { session, .. } | Session::Local(session) => session.advance_frame(),
+            Session::Spectator { session, .. } => session.advance_frame(),
+            Session::Rendezvous(_) | Session::Probing(_) => Err(GGRSError::NotSynchronized),
+        }
+    }
+    pub fn frames_ahead(&self) -> i32 {
+        match self {
+            Session::P2P { session, .. } => session.frames_ahead(),
+            Session::Rendezvous(_)
+            | Session::Probing(_)
+            | Session::SyncTest { .. }
+            | Session::Spectator { .. }
+            | Session::Local(_) => 0,
+        }
+    }
+    /// The last frame that can no longer be rolled back, given that `current_frame` has
+    /// just been simulated.
+    pub fn confirmed_frame(&self, current_frame: Frame) -> Frame {
+        match self {
+            Session::P2P { session, .. } => session.confirmed_frame(),
+            Session::SyncTest { check_distance, .. } => current_frame - *check_distance as Frame,
+            // Spectators only ever simulate confirmed inputs, and local inputs are never
+            // predicted.
+            Session::Spectator { .. } | Session::Local(_) => current_frame,
+            Session::Rendezvous(_) | Session::Probing(_) => -1,
+        }
+    }
+    pub fn poll_remote_clients(&mut self) {
+        match self {
+            Session::P2P { session, .. } => session.poll_remote_clients(),
+            Session::Rendezvous(rendezvous) => {
+                if let Some((local_handle, remote_addr)) = rendezvous.poll() {
+                    // Only there until the real session replaces it.
+                    let placeholder = Session::local(FrameRate::default());
+                    let Session::Rendezvous(rendezvous) = mem::replace(self, placeholder) else {
+                        unreachable!()
+                    };
+                    *self = rendezvous.start(local_handle, remote_addr);
+                }
+            }
+            Session::Probing(probe) => {
+                if let Some(input_delay) = probe.poll() {
+                    let placeholder = Session::local(FrameRate::default());
+                    let Session::Probing(probe) = mem::replace(self, placeholder) else {
+                        unreachable!()
+                    };
+                    *self = probe.start(input_delay);
+                }
+            }
+            Session::SyncTest { .. } | Session::Local(_) => {}
+            Session::Spectator { session, .. } => session.poll_remote_clients(),
+        }
+    }
+    /// Keeps the checksum of the snapshot just saved for `frame`, to compare with the other
+    /// player's once it is confirmed.
+    pub fn save_checksum(&mut self, frame: Frame, checksum: u128) {
+        if let Session::P2P { desyncs, .. } = self {
+            desyncs.save(frame, checksum);
+        }
+    }
+    /// Compares checksums with the other player up to `confirmed_frame`, reporting any
+    /// desync among the [`Session::events`].
+    pub fn compare_checksums(&mut self, confirmed_frame: Frame) {
+        if let Session::P2P { desyncs, .. } = self {
+            desyncs.confirm(confirmed_frame);
+        }
+    }
+    pub fn events(&mut self) -> Vec<GGRSEvent<GGRSConfig>> {
+        match self {
+            Session::P2P {
+                session, desyncs, ..
+            } => session.events().chain(desyncs.events()).collect(),
+            Session::Rendezvous(rendezvous) => mem::take(&mut rendezvous.events),
+            Session::Probing(probe) => mem::take(&mut probe.events),
+            Session::SyncTest { .. } | Session::Local(_) => vec![],
+            Session::Spectator { session, .. } => session.events().collect(),
+        }
+    }
+    /// Stats of the connection to the other player, or to the host when spectating.
+    pub fn network_stats(&self) -> Option<NetworkStats> {
+        match self {
+            Session::P2P {
+                session,
+                local_handle,
+                ..
+            } => session.network_stats(1 - local_handle).ok(),
+            Session::Rendezvous(_)
+            | Session::Probing(_)
+            | Session::SyncTest { .. }
+            | Session::Local(_) => None,
+            // A spectator is only connected to the host.
+            Session::Spectator { session, .. } => session.network_stats().ok(),
+        }
+    }
+    /// The datagrams dropped for failing the session key's checks so far.
+    pub fn rejected_packets(&self) -> RejectedPackets {
+        let rejected_packets = match self {
+            Session::P2P {
+                rejected_packets, ..
+            }
+            | Session::Spectator {
+                rejected_packets, ..
+            } => rejected_packets.clone(),
+            Session::Rendezvous(rendezvous) => rendezvous.socket.rejected_packets(),
+            Session::Probing(probe) => probe.socket.rejected_packets(),
+            Session::SyncTest { .. } | Session::Local(_) => return RejectedPackets::default(),
+        };
+        let rejected_packets = *rejected_packets.lock().unwrap();
+        rejected_packets
+    }
+}
+
+#[cfg(test)]
+mod tests;
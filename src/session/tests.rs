@@ -0,0 +1,421 @@
+//! Two P2P sessions in one process, joined by an in-memory connection instead of UDP, and
+//! driven with scripted inputs to check both peers agree on every confirmed frame, and
+//! report a desync only when they don't.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use counter_attack::sim::{self, FrameRate, GameState, SendInput, WorldSnapshot};
+use ggrs::{Frame, GGRSError, GGRSEvent, GGRSRequest, PlayerHandle, SessionState};
+
+use super::{InputDelay, NetSettings, P2PSettings, Session, Timeouts};
+use crate::{
+    conditioner::{ConditionedTransport, Conditions},
+    desync::{dump_desync, SnapshotHistory},
+    socket::{GameSocket, Transport},
+};
+
+const FRAMES: usize = 3000;
+const INPUT_DELAY: usize = 2;
+const CHECKSUM_INTERVAL: usize = 10;
+/// Long enough for a bad connection, but a hung test still fails rather than spinning.
+const TIME_LIMIT: Duration = Duration::from_secs(60);
+
+type Inbox = Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>;
+
+/// One end of an in-memory connection. Everything sent through it arrives at the other
+/// end, from this end's address, whatever address it was sent to.
+struct ChannelTransport {
+    local_addr: SocketAddr,
+    inbox: Inbox,
+    peer_inbox: Inbox,
+}
+
+fn channel(addrs: [SocketAddr; 2]) -> [ChannelTransport; 2] {
+    let inboxes = [Inbox::default(), Inbox::default()];
+    [0, 1].map(|side| ChannelTransport {
+        local_addr: addrs[side],
+        inbox: inboxes[side].clone(),
+        peer_inbox: inboxes[1 - side].clone(),
+    })
+}
+
+impl Transport for ChannelTransport {
+    fn send_to(&mut self, datagram: &[u8], _addr: SocketAddr) -> io::Result<()> {
+        let mut peer_inbox = self.peer_inbox.lock().unwrap();
+        peer_inbox.push_back((self.local_addr, datagram.to_vec()));
+        Ok(())
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some((src_addr, datagram)) = self.inbox.lock().unwrap().pop_front() else {
+            return Err(ErrorKind::WouldBlock.into());
+        };
+        buffer[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), src_addr))
+    }
+}
+
+/// Decides a player's input for the frame after the given one, which input delay makes
+/// later than the frame the input is taken on.
+type Script = fn(PlayerHandle, usize) -> SendInput;
+
+/// Swings every `interval` frames, at a different point within the frame every time.
+fn swings_every(interval: usize, frame: usize) -> SendInput {
+    let last_swing = frame - frame % interval;
+    let ticks_per_frame = FrameRate::default().ticks_per_frame() as u64;
+    SendInput {
+        swing_count: (frame / interval) as u32,
+        swing_offset: ((last_swing as u64 * 7919) % ticks_per_frame) as u32,
+    }
+}
+
+/// Swings at intervals that drift in and out of phase between the players, so attacks are
+/// both blocked and let through. Both intervals are longer than an attack takes to
+/// recover, so every swing is allowed.
+fn staggered_swings(handle: PlayerHandle, frame: usize) -> SendInput {
+    swings_every([71, 89][handle], frame)
+}
+
+/// Like [`staggered_swings`], except that the second player swings so often that some of
+/// their swings come before their attack has recovered.
+fn hasty_swings(handle: PlayerHandle, frame: usize) -> SendInput {
+    swings_every([71, 31][handle], frame)
+}
+
+/// One side of the match, doing what `rollback_system` does with the session's requests.
+struct Peer {
+    session: Session,
+    handle: PlayerHandle,
+    world: WorldSnapshot,
+    /// The snapshot last saved for each frame; a rollback replaces those after it.
+    saved: BTreeMap<Frame, WorldSnapshot>,
+    rollbacks: usize,
+    /// The frames the session reported as desynced.
+    desyncs: Vec<Frame>,
+    /// Breaks the simulation on this frame, as a nondeterministic bug would.
+    corrupt_frame: Option<usize>,
+    history: SnapshotHistory,
+    /// Where to dump desyncs earlier than any dumped yet, as `dump_desyncs` does.
+    dump_dir: Option<PathBuf>,
+    dumped: BTreeMap<Frame, PathBuf>,
+    /// Whether the session reported the other player gone.
+    disconnected: bool,
+}
+
+impl Peer {
+    fn new(handle: PlayerHandle, transport: impl Transport + 'static) -> Self {
+        Self::at(handle, transport, FrameRate::default())
+    }
+    fn at(
+        handle: PlayerHandle,
+        transport: impl Transport + 'static,
+        frame_rate: FrameRate,
+    ) -> Self {
+        let addrs = peer_addrs();
+        let settings = P2PSettings {
+            local_handle: handle,
+            local_port: addrs[handle].port(),
+            remote_addr: addrs[1 - handle],
+            spectators: vec![],
+            relay_session: None,
+            net: NetSettings {
+                frame_rate,
+                input_delay: InputDelay::Frames(INPUT_DELAY),
+                max_prediction: 8,
+                checksum_interval: CHECKSUM_INTERVAL,
+                timeouts: Timeouts {
+                    disconnect_notify_start: Duration::from_millis(500),
+                    disconnect_timeout: Duration::from_secs(5),
+                },
+                key: None,
+                conditions: None,
+            },
+        };
+        Self {
+            session: Session::p2p_on(settings, GameSocket::new(transport)).unwrap(),
+            handle,
+            world: WorldSnapshot::new(frame_rate),
+            saved: BTreeMap::new(),
+            rollbacks: 0,
+            desyncs: vec![],
+            corrupt_frame: None,
+            history: SnapshotHistory::default(),
+            dump_dir: None,
+            dumped: BTreeMap::new(),
+            disconnected: false,
+        }
+    }
+
+    /// The last frame this peer has saved with nothing predicted. GGRS's own confirmed
+    /// frame can run ahead of what this peer has simulated.
+    fn confirmed_frame(&self) -> Frame {
+        let last_saved = self.saved.keys().next_back().map_or(-1, |&frame| frame);
+        let confirmed = self.session.confirmed_frame(self.world.frame as Frame);
+        confirmed.min(last_saved)
+    }
+
+    fn step(&mut self, script: Script) {
+        self.session.poll_remote_clients();
+        for event in self.session.events() {
+            if let GGRSEvent::Disconnected { .. } = event {
+                self.disconnected = true;
+            }
+            if let GGRSEvent::DesyncDetected {
+                frame,
+                local_checksum,
+                remote_checksum,
+                addr,
+            } = event
+            {
+                self.desyncs.push(frame);
+                let earliest = self.dumped.keys().next().copied();
+                let Some(dir) = &self.dump_dir else {
+                    continue;
+                };
+                if earliest.is_none_or(|earliest| frame < earliest) {
+                    let path = dump_desync(
+                        &self.history,
+                        dir,
+                        frame,
+                        local_checksum,
+                        remote_checksum,
+                        addr,
+                    );
+                    self.dumped.insert(frame, path.unwrap());
+                }
+            }
+        }
+        if self.session.current_state() != SessionState::Running {
+            return;
+        }
+        let input = script(self.handle, self.world.frame + INPUT_DELAY);
+        self.session.add_local_input(self.handle, input).unwrap();
+        let requests = match self.session.advance_frame() {
+            Ok(requests) => requests,
+            Err(GGRSError::PredictionThreshold) => return,
+            Err(err) => panic!("player {} couldn't advance: {err}", self.handle),
+        };
+        for request in requests {
+            match request {
+                GGRSRequest::SaveGameState { cell, frame } => {
+                    assert_eq!(self.world.frame as Frame, frame);
+                    let checksum = self.world.checksum();
+                    self.session.save_checksum(frame, checksum);
+                    self.saved.insert(frame, self.world.clone());
+                    self.history.save(self.world.clone());
+                    cell.save(frame, Some(self.world.clone()), Some(checksum));
+                }
+                GGRSRequest::LoadGameState { cell, .. } => {
+                    self.world = cell.load().unwrap();
+                    self.rollbacks += 1;
+                }
+                GGRSRequest::AdvanceFrame { inputs } => {
+                    sim::simulate(&mut self.world, [inputs[0].0, inputs[1].0]);
+                    if self.corrupt_frame == Some(self.world.frame) {
+                        self.world.players[0].invalid_swings += 1;
+                    }
+                }
+            }
+        }
+        self.session.compare_checksums(self.confirmed_frame());
+    }
+}
+
+fn peer_addrs() -> [SocketAddr; 2] {
+    [
+        "127.0.0.1:7000".parse().unwrap(),
+        "127.0.0.1:7001".parse().unwrap(),
+    ]
+}
+
+/// Plays `FRAMES` frames of `script` between two peers, until both have confirmed them.
+fn play(transports: [Box<dyn Transport>; 2], script: Script) -> [Peer; 2] {
+    let [first, second] = transports;
+    play_between([Peer::new(0, first), Peer::new(1, second)], script)
+}
+
+fn play_between(mut peers: [Peer; 2], script: Script) -> [Peer; 2] {
+    let start = Instant::now();
+    while peers
+        .iter()
+        .any(|peer| peer.confirmed_frame() < FRAMES as Frame)
+    {
+        assert!(
+            start.elapsed() < TIME_LIMIT,
+            "only confirmed frames {} and {} in time",
+            peers[0].confirmed_frame(),
+            peers[1].confirmed_frame(),
+        );
+        for peer in &mut peers {
+            peer.step(script);
+        }
+        // Give delayed datagrams a chance to fall due while both sides wait on each other.
+        std::thread::sleep(Duration::from_micros(200));
+    }
+    peers
+}
+
+/// Checks the peers saved the same snapshot for every confirmed frame, returning the last.
+fn assert_agree(peers: &[Peer; 2]) -> WorldSnapshot {
+    for frame in 0..=FRAMES as Frame {
+        let [first, second] = [0, 1].map(|handle| &peers[handle].saved[&frame]);
+        assert_eq!(
+            first.checksum(),
+            second.checksum(),
+            "the peers' checksums differ at frame {frame}:\n{first:?}\n{second:?}"
+        );
+    }
+    let [first, second] = [0, 1].map(|handle| &peers[handle].saved[&(FRAMES as Frame)]);
+    assert_eq!(first.game_state, second.game_state);
+    first.clone()
+}
+
+#[test]
+fn peers_agree_over_a_perfect_connection() {
+    let [first, second] = channel(peer_addrs());
+    let peers = play([Box::new(first), Box::new(second)], staggered_swings);
+    let last = assert_agree(&peers);
+    // Otherwise the script hasn't exercised much of the rules.
+    assert_ne!(last.game_state, GameState::Playing);
+    assert_eq!(
+        last.players.each_ref().map(|player| player.invalid_swings),
+        [0, 0]
+    );
+}
+
+#[test]
+fn peers_agree_over_a_bad_connection() {
+    let conditions = Conditions {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(10),
+        loss: 0.05,
+        duplication: 0.05,
+        reordering: 0.05,
+        seed: Some(7),
+    };
+    let transports = channel(peer_addrs()).map(|transport| -> Box<dyn Transport> {
+        Box::new(ConditionedTransport::new(transport, conditions))
+    });
+    let peers = play(transports, staggered_swings);
+    assert_agree(&peers);
+    assert!(
+        peers.iter().any(|peer| peer.rollbacks > 0),
+        "nothing was rolled back"
+    );
+    // Rolling back predicted frames is no desync.
+    for peer in &peers {
+        assert!(
+            peer.desyncs.is_empty(),
+            "player {} saw desyncs",
+            peer.handle
+        );
+    }
+}
+
+#[test]
+fn peers_report_every_checked_desynced_frame_once_over_a_lossy_connection() {
+    const CORRUPT_FRAME: usize = 1001;
+    // The snapshot saved for the corrupted frame is already off, but only every
+    // `CHECKSUM_INTERVAL`th frame is checked.
+    let first_checked = CORRUPT_FRAME.next_multiple_of(CHECKSUM_INTERVAL) as Frame;
+    let conditions = Conditions {
+        loss: 0.2,
+        seed: Some(4),
+        ..Conditions::default()
+    };
+    let [first, second] =
+        channel(peer_addrs()).map(|transport| ConditionedTransport::new(transport, conditions));
+    let mut peers = [Peer::new(0, first), Peer::new(1, second)];
+    peers[1].corrupt_frame = Some(CORRUPT_FRAME);
+    let dump_dirs = [0, 1].map(|handle| {
+        let dir =
+            std::env::temp_dir().join(format!("counter-attack-desync-{}-{handle}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    });
+    for (peer, dir) in peers.iter_mut().zip(&dump_dirs) {
+        peer.dump_dir = Some(dir.clone());
+    }
+
+    let peers = play_between(peers, staggered_swings);
+
+    for peer in &peers {
+        // Resent checksums can arrive after later frames' have.
+        let mut desyncs = peer.desyncs.clone();
+        desyncs.sort();
+        assert_eq!(
+            desyncs.first(),
+            Some(&first_checked),
+            "player {}",
+            peer.handle
+        );
+        // Each once, and every checked frame since despite the lost datagrams, but for the
+        // last few, whose checksums may still be on their way when the match stops.
+        assert!(
+            desyncs.windows(2).all(|pair| pair[0] < pair[1]),
+            "{desyncs:?}"
+        );
+        let settled = (FRAMES - 200) as Frame;
+        let expected: Vec<Frame> = (first_checked..settled)
+            .step_by(CHECKSUM_INTERVAL)
+            .collect();
+        assert_eq!(
+            desyncs[..expected.len()],
+            expected,
+            "player {}",
+            peer.handle
+        );
+
+        let (&earliest, dumped) = peer.dumped.first_key_value().unwrap();
+        assert_eq!(earliest, first_checked, "player {}", peer.handle);
+        let name = dumped.file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with(&format!("desync-{first_checked}-")),
+            "{name}"
+        );
+        assert!(dumped.exists());
+        assert!(dumped.with_extension("bin").exists());
+    }
+    for dir in dump_dirs {
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn peers_agree_on_ignoring_a_hasty_swing() {
+    let [first, second] = channel(peer_addrs());
+    let peers = play([Box::new(first), Box::new(second)], hasty_swings);
+    let last = assert_agree(&peers);
+    assert_eq!(last.players[0].invalid_swings, 0);
+    assert!(last.players[1].invalid_swings > 0);
+}
+
+#[test]
+fn peers_at_different_frame_rates_refuse_to_start() {
+    let [first, second] = channel(peer_addrs());
+    let mut peers = [
+        Peer::at(0, first, FrameRate::from_fps(60).unwrap()),
+        Peer::at(1, second, FrameRate::from_fps(30).unwrap()),
+    ];
+    let start = Instant::now();
+    while !peers.iter().all(|peer| peer.disconnected) {
+        assert!(start.elapsed() < TIME_LIMIT, "the peers never refused");
+        for peer in &mut peers {
+            peer.step(staggered_swings);
+            assert_eq!(peer.session.current_state(), SessionState::Synchronizing);
+        }
+    }
+    // Still refusing once the other player has given up too.
+    for peer in &mut peers {
+        peer.step(staggered_swings);
+        assert_eq!(peer.session.current_state(), SessionState::Synchronizing);
+    }
+}
@@ -0,0 +1,555 @@
+//! The duel rules, independent of Bevy.
+//!
+//! Everything that decides the outcome of a match lives here so it can be driven by the
+//! rollback session, a bot, a replay or a server alike. The ECS only copies a
+//! [`WorldSnapshot`] in and out and turns [`SimEvent`]s into sounds and UI.
+//!
+//! All simulation time is integer [`TICKS_PER_SECOND`] ticks so that every peer computes
+//! bit-for-bit the same result. Floating point only appears at the presentation edges.
+//! How many ticks a frame lasts is the snapshot's [`FrameRate`], so matches at different
+//! rates can be simulated side by side.
+
+pub mod bot;
+mod checksum;
+pub mod format;
+pub mod replay;
+
+use std::{
+    fmt,
+    ops::{Add, Neg, Sub},
+};
+
+use bytemuck::{Pod, Zeroable};
+use ggrs::PlayerHandle;
+use iunorm::Unorm64;
+use serde::{Deserialize, Serialize};
+
+/// Chosen so that a frame is a whole number of ticks at every common frame rate.
+pub const TICKS_PER_SECOND: i64 = 720_000;
+pub const DEFAULT_FPS: usize = 60;
+pub const BASE_STAMINA_LOSS: Unorm64 = Unorm64(u64::MAX / 10);
+pub const CLASH_LENGTH: Second = Second::from_millis(1000);
+pub const FINAL_CLASH_LIVES: u8 = 4;
+
+pub const TEST_ATTACK: Attack = Attack {
+    startup_time: Second::from_millis(900),
+    block_grace: Second::from_millis(300),
+    recover_time: Second::from_millis(200),
+};
+
+/// Whether a frame at `fps` frames per second is a whole number of ticks long.
+pub fn supports_fps(fps: usize) -> bool {
+    fps > 0 && TICKS_PER_SECOND % fps as i64 == 0
+}
+
+/// How many frames are simulated per second, as the whole number of ticks each one lasts.
+/// Every peer has to use the same rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FrameRate {
+    ticks_per_frame: i64,
+}
+
+impl FrameRate {
+    /// `fps` frames per second, if that is [supported](supports_fps).
+    pub fn from_fps(fps: usize) -> Option<Self> {
+        supports_fps(fps).then(|| Self {
+            ticks_per_frame: TICKS_PER_SECOND / fps as i64,
+        })
+    }
+    /// Frames `frametime` long, if that many fit exactly in a second.
+    pub fn from_frametime(frametime: Second) -> Option<Self> {
+        let ticks_per_frame = frametime.0;
+        (ticks_per_frame > 0 && TICKS_PER_SECOND % ticks_per_frame == 0)
+            .then_some(Self { ticks_per_frame })
+    }
+    pub fn fps(self) -> usize {
+        (TICKS_PER_SECOND / self.ticks_per_frame) as usize
+    }
+    pub fn ticks_per_frame(self) -> i64 {
+        self.ticks_per_frame
+    }
+    /// The length of one frame.
+    pub fn frametime(self) -> Second {
+        Second(self.ticks_per_frame)
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        Self::from_fps(DEFAULT_FPS).unwrap()
+    }
+}
+
+/// A span of simulation time, stored as a whole number of ticks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Second(pub i64);
+
+impl Second {
+    pub const ZERO: Self = Self(0);
+    pub const ONE: Self = Self(TICKS_PER_SECOND);
+
+    pub const fn from_millis(millis: i64) -> Self {
+        Self(millis * TICKS_PER_SECOND / 1000)
+    }
+    /// Rounds to the nearest tick. Only for input coming from the outside world, such as
+    /// wall-clock time; the simulation itself never converts from floats.
+    pub fn from_secs_f64(seconds: f64) -> Self {
+        Self((seconds * TICKS_PER_SECOND as f64).round() as i64)
+    }
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / TICKS_PER_SECOND as f64
+    }
+    pub fn as_secs_f32(self) -> f32 {
+        self.as_secs_f64() as f32
+    }
+    pub fn abs(self) -> Self {
+        Self(self.0.abs())
+    }
+}
+impl Neg for Second {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self(-self.0)
+    }
+}
+impl Add for Second {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self(self.0 + rhs.0)
+    }
+}
+impl Sub for Second {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self(self.0 - rhs.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attack {
+    pub startup_time: Second,
+    pub block_grace: Second,
+    pub recover_time: Second,
+}
+
+/// A point in simulation time: a frame plus the number of ticks into that frame.
+///
+/// `offset` is always less than the [`FrameRate`]'s ticks per frame, so the derived
+/// ordering is chronological. Moving by a [`Second`] needs that rate too.
+#[repr(C)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Pod, Zeroable, Hash, Serialize, Deserialize,
+)]
+pub struct FrameOffset {
+    pub frame: usize,
+    pub offset: u64,
+}
+impl FrameOffset {
+    /// The very start of `frame`.
+    pub fn at_frame(frame: usize) -> Self {
+        Self { frame, offset: 0 }
+    }
+    /// `span` later at `rate`, or earlier for a negative span.
+    pub fn after(self, span: Second, rate: FrameRate) -> Self {
+        let ticks = self.offset as i64 + span.0;
+        Self {
+            frame: (self.frame as i64 + ticks.div_euclid(rate.ticks_per_frame)) as usize,
+            offset: ticks.rem_euclid(rate.ticks_per_frame) as u64,
+        }
+    }
+    /// How long after `self` `future` is at `rate`, negative if it is earlier.
+    pub fn get_offset_seconds(&self, future: &Self, rate: FrameRate) -> Second {
+        let frames = future.frame as i64 - self.frame as i64;
+        Second(frames * rate.ticks_per_frame + future.offset as i64 - self.offset as i64)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Player {
+    pub current_attack: Option<Attack>,
+    pub attack_start_time: FrameOffset,
+    pub attack_recover_time: FrameOffset,
+    pub last_defend_result: Second,
+    #[serde(with = "format::unorm64")]
+    pub stamina: Unorm64,
+    pub final_clash_lives: u8,
+    pub final_clash_last_swing: Option<FrameOffset>,
+    /// Swings [`simulate`] ignored because the rules don't allow them. A client playing
+    /// by the rules never sends those, so this flags one that doesn't.
+    pub invalid_swings: u32,
+    /// The [`SendInput::swing_count`] of this player's last input.
+    pub swing_count: u32,
+}
+impl Player {
+    pub fn new(now: FrameOffset) -> Self {
+        Self {
+            current_attack: None,
+            attack_start_time: now,
+            attack_recover_time: now,
+            last_defend_result: Second::ZERO,
+            stamina: Unorm64(u64::MAX),
+            final_clash_lives: FINAL_CLASH_LIVES,
+            final_clash_last_swing: None,
+            invalid_swings: 0,
+            swing_count: 0,
+        }
+    }
+    /// Input from this player that doesn't swing.
+    pub fn idle_input(&self) -> SendInput {
+        SendInput {
+            swing_count: self.swing_count,
+            swing_offset: 0,
+        }
+    }
+    /// Whether this player's own attack has recovered by `at`, so that they may swing.
+    pub fn can_swing_at(&self, at: FrameOffset) -> bool {
+        self.current_attack.is_none() || at > self.attack_recover_time
+    }
+    /// Starts `attack` at `frame_offset`. If `other` has an attack in flight this swing
+    /// blocks it, and the offset from its impact time is returned.
+    pub fn swing(
+        &mut self,
+        other: &mut Self,
+        frame_offset: FrameOffset,
+        attack: Attack,
+        rate: FrameRate,
+    ) -> Option<Second> {
+        self.attack_start_time = frame_offset;
+        self.attack_recover_time =
+            frame_offset.after(attack.startup_time + attack.recover_time, rate);
+
+        self.current_attack = Some(attack);
+
+        let defend_time = frame_offset;
+        let impact_time = other
+            .attack_start_time
+            .after(other.current_attack.as_ref()?.startup_time, rate);
+
+        let defend_time_offset = defend_time.get_offset_seconds(&impact_time, rate);
+        other.current_attack = None;
+        self.last_defend_result = defend_time_offset;
+        Some(defend_time_offset)
+    }
+    pub fn take_final_clash_life(&mut self) {
+        if self.final_clash_lives > 0 {
+            self.final_clash_lives -= 1;
+        }
+    }
+}
+
+/// One player's input for one frame.
+///
+/// A swing is an input whose count differs from the player's previous one, rather than
+/// one with a swing in it, so the same input sent twice only swings once. GGRS predicts
+/// a remote player's input by repeating their last one, and stands in zeroed input for
+/// frames nobody sent any for; neither ever swings.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Pod, Zeroable, Serialize, Deserialize)]
+pub struct SendInput {
+    /// How many times the player has swung so far, wrapping around.
+    pub swing_count: u32,
+    /// How many ticks into the frame the latest swing came.
+    pub swing_offset: u32,
+}
+
+impl SendInput {
+    /// The input after this one, swinging `at`. Only the offset into its frame is sent,
+    /// as the swing is made during the frame the input is for.
+    pub fn swung(self, at: FrameOffset) -> Self {
+        Self {
+            swing_count: self.swing_count.wrapping_add(1),
+            swing_offset: at.offset as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FinalClash {
+    pub next_clash: Option<FrameOffset>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum GameState {
+    #[default]
+    Playing,
+    FinalClash,
+    Over,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub frame_rate: FrameRate,
+    /// The last frame that has been simulated.
+    pub frame: usize,
+    /// Indexed by player handle.
+    pub players: [Player; 2],
+    pub final_clash: FinalClash,
+    pub game_state: GameState,
+}
+
+impl WorldSnapshot {
+    /// The start of a match played at `frame_rate`.
+    pub fn new(frame_rate: FrameRate) -> Self {
+        let now = FrameOffset::at_frame(0);
+        Self {
+            frame_rate,
+            frame: 0,
+            players: [Player::new(now), Player::new(now)],
+            final_clash: FinalClash { next_clash: None },
+            game_state: GameState::default(),
+        }
+    }
+}
+
+impl Default for WorldSnapshot {
+    fn default() -> Self {
+        Self::new(FrameRate::default())
+    }
+}
+
+/// Something that happened during [`simulate`] that the presentation layer may want to show.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimEvent {
+    /// `handle` swung into the other player's attack. `quality` is 1.0 for a swing that
+    /// landed exactly on impact and falls off to 0.0 a second either side of it.
+    Block {
+        handle: PlayerHandle,
+        quality: f32,
+    },
+    /// `handle` was cut during the final clash because only the other player swung.
+    FinalClashCut {
+        handle: PlayerHandle,
+    },
+    /// Both players swung during a final clash round.
+    FinalClashParry,
+    FinalClashBegan,
+    GameOver {
+        loser: Option<PlayerHandle>,
+    },
+    /// `loser` left the match before it was over.
+    Forfeit {
+        loser: PlayerHandle,
+    },
+    /// `handle` sent a swing the rules don't allow, which was ignored.
+    InvalidSwing {
+        handle: PlayerHandle,
+        reason: InvalidSwing,
+    },
+}
+
+/// Why [`simulate`] ignored a swing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidSwing {
+    /// Its offset is past the end of the frame it was sent for.
+    OutsideFrame,
+    /// It came before the player's own attack had recovered.
+    DuringRecovery,
+}
+
+impl fmt::Display for InvalidSwing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            InvalidSwing::OutsideFrame => "timestamped past the end of the frame it was sent for",
+            InvalidSwing::DuringRecovery => "made before their attack recovered",
+        })
+    }
+}
+
+/// Stamina lost by a blocker whose swing was `error` away from impact: a fifth of
+/// [`BASE_STAMINA_LOSS`] for a perfect block, rising linearly to all of it a second off.
+fn block_stamina_loss(error: Second) -> Unorm64 {
+    let error = error.abs().min(Second::ONE).0 as u128;
+    let ticks_per_second = TICKS_PER_SECOND as u128;
+    let loss =
+        BASE_STAMINA_LOSS.0 as u128 * (ticks_per_second + 4 * error) / (5 * ticks_per_second);
+    Unorm64(loss as u64)
+}
+
+/// Splits `players` into the player at `handle` and their opponent.
+fn player_pair(players: &mut [Player; 2], handle: PlayerHandle) -> (&mut Player, &mut Player) {
+    let [first, second] = players;
+    if handle == 0 {
+        (first, second)
+    } else {
+        (second, first)
+    }
+}
+
+/// When `input` swings during the frame that ends at `now`, if it does and `player` can
+/// swing then. Takes note of its swing count either way.
+fn take_swing(
+    player: &mut Player,
+    input: SendInput,
+    now: FrameOffset,
+    rate: FrameRate,
+) -> Result<Option<FrameOffset>, InvalidSwing> {
+    if input.swing_count == player.swing_count {
+        return Ok(None);
+    }
+    player.swing_count = input.swing_count;
+    if input.swing_offset as i64 >= rate.ticks_per_frame {
+        return Err(InvalidSwing::OutsideFrame);
+    }
+    let at = FrameOffset {
+        frame: now.frame - 1,
+        offset: input.swing_offset as u64,
+    };
+    if !player.can_swing_at(at) {
+        return Err(InvalidSwing::DuringRecovery);
+    }
+    Ok(Some(at))
+}
+
+/// Advances `world` by one frame using each player's input, indexed by handle. A swing
+/// must fall within the frame being simulated and come once the player's attack has
+/// recovered; any other is ignored and counted against the player.
+pub fn simulate(world: &mut WorldSnapshot, inputs: [SendInput; 2]) -> Vec<SimEvent> {
+    let mut events = vec![];
+
+    world.frame += 1;
+    if world.game_state == GameState::Over {
+        return events;
+    }
+    let rate = world.frame_rate;
+    let now = FrameOffset::at_frame(world.frame);
+    if world.game_state == GameState::FinalClash && world.final_clash.next_clash.is_none() {
+        world.final_clash.next_clash = Some(now.after(CLASH_LENGTH, rate));
+    }
+
+    for (handle, input) in inputs.into_iter().enumerate() {
+        let (current_player, other_player) = player_pair(&mut world.players, handle);
+        let swing = match take_swing(current_player, input, now, rate) {
+            Ok(swing) => swing,
+            Err(reason) => {
+                current_player.invalid_swings += 1;
+                events.push(SimEvent::InvalidSwing { handle, reason });
+                None
+            }
+        };
+
+        if world.game_state == GameState::FinalClash {
+            if swing.is_some() && current_player.final_clash_last_swing.is_none() {
+                current_player.final_clash_last_swing = swing;
+            }
+            continue;
+        }
+
+        let mut stamina_loss = Unorm64(0);
+        if let Some(swing) = swing {
+            let swing_result = current_player.swing(other_player, swing, TEST_ATTACK, rate);
+            if let Some(swing_result) = swing_result {
+                let error = swing_result.abs().min(Second::ONE);
+                events.push(SimEvent::Block {
+                    handle,
+                    quality: 1.0 - error.as_secs_f32(),
+                });
+                stamina_loss = block_stamina_loss(error);
+            }
+        } else if let Some(current_attack) = &other_player.current_attack {
+            let grace_over = other_player.attack_start_time.after(
+                current_attack.startup_time + current_attack.block_grace,
+                rate,
+            );
+            if now > grace_over {
+                stamina_loss = Unorm64(BASE_STAMINA_LOSS.0 / 2 * 3);
+                other_player.current_attack = None;
+            }
+        }
+        if stamina_loss < current_player.stamina {
+            current_player.stamina.0 -= stamina_loss.0;
+        } else if stamina_loss.0 > 0 && current_player.stamina == Unorm64(0) {
+            events.push(SimEvent::GameOver {
+                loser: Some(handle),
+            });
+        } else {
+            current_player.stamina = Unorm64(0);
+        }
+    }
+
+    if events
+        .iter()
+        .any(|event| matches!(event, SimEvent::GameOver { .. }))
+    {
+        world.game_state = GameState::Over;
+    } else if world.game_state == GameState::FinalClash {
+        resolve_final_clash(world, now, &mut events);
+    } else if world
+        .players
+        .iter()
+        .all(|player| player.stamina == Unorm64(0))
+    {
+        world.game_state = GameState::FinalClash;
+        events.push(SimEvent::FinalClashBegan);
+    }
+
+    events
+}
+
+/// Ends the match in favour of `handle`'s opponent, because `handle` has left it. Run after
+/// [`simulate`] on every frame that `handle` is gone for.
+pub fn forfeit(world: &mut WorldSnapshot, handle: PlayerHandle) -> Vec<SimEvent> {
+    if world.game_state == GameState::Over {
+        return vec![];
+    }
+    world.game_state = GameState::Over;
+    vec![SimEvent::Forfeit { loser: handle }]
+}
+
+fn resolve_final_clash(world: &mut WorldSnapshot, now: FrameOffset, events: &mut Vec<SimEvent>) {
+    let Some(next_clash) = world.final_clash.next_clash else {
+        return;
+    };
+    let rate = world.frame_rate;
+    let swings = world
+        .players
+        .clone()
+        .map(|player| player.final_clash_last_swing);
+    if now.get_offset_seconds(&next_clash, rate) < -CLASH_LENGTH {
+        for handle in 0..2 {
+            if swings[handle].is_none() {
+                world.players[handle].take_final_clash_life();
+                if swings[1 - handle].is_some() {
+                    events.push(SimEvent::FinalClashCut { handle });
+                }
+            }
+        }
+        end_final_clash_round(world);
+    } else if let [Some(first_clash), Some(second_clash)] = swings {
+        let first_offset = first_clash.get_offset_seconds(&next_clash, rate);
+        let second_offset = second_clash.get_offset_seconds(&next_clash, rate);
+        if first_offset.abs() < second_offset.abs() {
+            world.players[1].take_final_clash_life()
+        }
+        if second_offset.abs() < first_offset.abs() {
+            world.players[0].take_final_clash_life()
+        }
+        events.push(SimEvent::FinalClashParry);
+        end_final_clash_round(world);
+    }
+
+    let loser = match world
+        .players
+        .clone()
+        .map(|player| player.final_clash_lives == 0)
+    {
+        [true, true] => None,
+        [true, false] => Some(0),
+        [false, true] => Some(1),
+        [false, false] => return,
+    };
+    events.push(SimEvent::GameOver { loser });
+    world.game_state = GameState::Over;
+}
+
+fn end_final_clash_round(world: &mut WorldSnapshot) {
+    world.final_clash.next_clash = None;
+    for player in &mut world.players {
+        player.final_clash_last_swing = None;
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,232 @@
+//! A computer opponent that plays through [`SendInput`]s like anyone else.
+//!
+//! The bot looks at the last simulated [`WorldSnapshot`] and aims its counter-swing at the
+//! moment the opponent's attack lands, off by a random error drawn from its
+//! [`BotTiming`]. When neither player is attacking it starts an attack of its own after a
+//! random pause.
+
+use std::{fmt, str::FromStr};
+
+use ggrs::PlayerHandle;
+
+use super::{FrameOffset, FrameRate, GameState, Player, Second, SendInput, WorldSnapshot};
+use crate::rng::SplitMix64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+    /// Never misses impact by a single tick.
+    Inhuman,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [
+        Difficulty::Easy,
+        Difficulty::Normal,
+        Difficulty::Hard,
+        Difficulty::Inhuman,
+    ];
+
+    pub fn timing(self) -> BotTiming {
+        let (jitter, min_attack_delay, max_attack_delay) = match self {
+            Difficulty::Easy => (150, 1500, 3000),
+            Difficulty::Normal => (60, 1000, 2000),
+            Difficulty::Hard => (20, 500, 1500),
+            Difficulty::Inhuman => (0, 300, 300),
+        };
+        BotTiming {
+            jitter: Second::from_millis(jitter),
+            min_attack_delay: Second::from_millis(min_attack_delay),
+            max_attack_delay: Second::from_millis(max_attack_delay),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+            Difficulty::Inhuman => "inhuman",
+        })
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Difficulty::ALL
+            .into_iter()
+            .find(|difficulty| difficulty.to_string() == s)
+            .ok_or_else(|| {
+                format!("unknown difficulty {s:?}, expected easy, normal, hard or inhuman")
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BotTiming {
+    /// Swings land uniformly within this far either side of the time aimed for.
+    pub jitter: Second,
+    /// How long to wait before attacking once neither player is.
+    pub min_attack_delay: Second,
+    pub max_attack_delay: Second,
+}
+
+/// A planned swing, and what it was planned in response to so it can be dropped once
+/// that changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Plan {
+    /// Block the attack the opponent started at `attack_start_time`.
+    Counter {
+        attack_start_time: FrameOffset,
+        at: FrameOffset,
+    },
+    /// Start an attack while both players are idle.
+    Attack { at: FrameOffset },
+    /// Swing in the final clash round that ends at `next_clash`.
+    FinalClash {
+        next_clash: FrameOffset,
+        at: FrameOffset,
+    },
+}
+
+impl Plan {
+    fn at(self) -> FrameOffset {
+        match self {
+            Plan::Counter { at, .. } | Plan::Attack { at } | Plan::FinalClash { at, .. } => at,
+        }
+    }
+}
+
+pub struct Bot {
+    handle: PlayerHandle,
+    timing: BotTiming,
+    rng: SplitMix64,
+    plan: Option<Plan>,
+    /// The bot's last input, which the next one's swing count follows on from.
+    last_input: SendInput,
+}
+
+impl Bot {
+    /// A bot playing as `handle`. The same `seed` against the same inputs always makes the
+    /// same swings.
+    pub fn new(handle: PlayerHandle, timing: BotTiming, seed: u64) -> Self {
+        Self {
+            handle,
+            timing,
+            rng: SplitMix64::new(seed),
+            plan: None,
+            last_input: SendInput::default(),
+        }
+    }
+    pub fn handle(&self) -> PlayerHandle {
+        self.handle
+    }
+
+    /// The bot's input for the frame after `world`, swinging somewhere within that frame
+    /// if a planned swing falls due.
+    pub fn input(&mut self, world: &WorldSnapshot) -> SendInput {
+        let frame_start = FrameOffset::at_frame(world.frame);
+        let frame_end = FrameOffset::at_frame(world.frame + 1);
+
+        self.plan = self.next_plan(world, frame_start);
+        if let Some(plan) = self.plan.filter(|plan| plan.at() < frame_end) {
+            self.plan = None;
+            self.last_input = self.last_input.swung(plan.at().max(frame_start));
+        }
+        self.last_input
+    }
+
+    fn next_plan(&mut self, world: &WorldSnapshot, frame_start: FrameOffset) -> Option<Plan> {
+        let me = &world.players[self.handle];
+        let opponent = &world.players[1 - self.handle];
+        let rate = world.frame_rate;
+
+        match world.game_state {
+            GameState::Over => None,
+            GameState::FinalClash => {
+                let next_clash = world.final_clash.next_clash?;
+                if me.final_clash_last_swing.is_some() {
+                    return None;
+                }
+                match self.plan {
+                    Some(
+                        plan @ Plan::FinalClash {
+                            next_clash: planned,
+                            ..
+                        },
+                    ) if planned == next_clash => Some(plan),
+                    _ => Some(Plan::FinalClash {
+                        next_clash,
+                        at: next_clash.after(self.jitter(), rate),
+                    }),
+                }
+            }
+            GameState::Playing => {
+                if let Some(attack) = &opponent.current_attack {
+                    let attack_start_time = opponent.attack_start_time;
+                    if let Some(
+                        plan @ Plan::Counter {
+                            attack_start_time: planned,
+                            ..
+                        },
+                    ) = self.plan
+                    {
+                        if planned == attack_start_time {
+                            return Some(plan);
+                        }
+                    }
+                    let impact_time = attack_start_time.after(attack.startup_time, rate);
+                    Some(Plan::Counter {
+                        attack_start_time,
+                        at: impact_time
+                            .after(self.jitter(), rate)
+                            .max(earliest_swing(me, rate)),
+                    })
+                } else if me.current_attack.is_none() {
+                    match self.plan {
+                        Some(plan @ Plan::Attack { .. }) => Some(plan),
+                        _ => {
+                            let delay = self.uniform(
+                                self.timing.min_attack_delay,
+                                self.timing.max_attack_delay,
+                            );
+                            Some(Plan::Attack {
+                                at: frame_start.after(delay, rate),
+                            })
+                        }
+                    }
+                } else {
+                    // Our own attack is in flight.
+                    None
+                }
+            }
+        }
+    }
+
+    fn jitter(&mut self) -> Second {
+        self.uniform(-self.timing.jitter, self.timing.jitter)
+    }
+    /// A uniformly random span between `min` and `max` inclusive.
+    fn uniform(&mut self, min: Second, max: Second) -> Second {
+        let range = (max.0 - min.0).max(0) as u64 + 1;
+        min + Second((self.rng.next_u64() % range) as i64)
+    }
+}
+
+/// Players can't swing again until their own attack has recovered.
+fn earliest_swing(player: &Player, rate: FrameRate) -> FrameOffset {
+    if player.current_attack.is_some() {
+        player.attack_recover_time.after(Second(1), rate)
+    } else {
+        FrameOffset::at_frame(0)
+    }
+}
+
+#[cfg(test)]
+mod tests;
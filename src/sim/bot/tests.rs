@@ -0,0 +1,88 @@
+//! The bot, played against a scripted opponent with a fixed seed.
+
+use super::*;
+use crate::sim::{simulate, SimEvent};
+
+const SEED: u64 = 7;
+
+/// Plays a `difficulty` bot as player 1 against a player 0 who attacks a third of the way
+/// into the frame whenever neither of them has an attack in flight, and never blocks.
+/// Returns how far from impact each of the bot's first `blocks` counters came.
+fn counter_errors(difficulty: Difficulty, blocks: usize) -> Vec<Second> {
+    let mut world = WorldSnapshot::default();
+    let mut bot = Bot::new(1, difficulty.timing(), SEED);
+    let offset = world.frame_rate.ticks_per_frame() as u64 / 3;
+    let mut errors = vec![];
+    while errors.len() < blocks {
+        assert_eq!(
+            world.game_state,
+            GameState::Playing,
+            "the match left play after {} blocks",
+            errors.len()
+        );
+        let mut attack = world.players[0].idle_input();
+        if world
+            .players
+            .iter()
+            .all(|player| player.current_attack.is_none())
+        {
+            let frame = world.frame;
+            attack = attack.swung(FrameOffset { frame, offset });
+        }
+        let counter = bot.input(&world);
+        for event in simulate(&mut world, [attack, counter]) {
+            if let SimEvent::Block { handle: 1, .. } = event {
+                errors.push(world.players[1].last_defend_result);
+            }
+        }
+    }
+    errors
+}
+
+#[test]
+fn an_inhuman_bot_counters_on_the_tick_of_impact() {
+    assert_eq!(counter_errors(Difficulty::Inhuman, 5), [Second::ZERO; 5]);
+}
+
+#[test]
+fn counters_miss_impact_by_no_more_than_the_difficultys_jitter() {
+    for (difficulty, jitter) in [
+        (Difficulty::Easy, 150),
+        (Difficulty::Normal, 60),
+        (Difficulty::Hard, 20),
+        (Difficulty::Inhuman, 0),
+    ] {
+        let errors = counter_errors(difficulty, 5);
+        let jitter = Second::from_millis(jitter);
+        assert!(
+            errors.iter().all(|error| error.abs() <= jitter),
+            "{difficulty} bot missed impact by {errors:?}"
+        );
+        assert!(
+            jitter == Second::ZERO || errors.iter().any(|&error| error != Second::ZERO),
+            "{difficulty} bot never missed impact"
+        );
+    }
+}
+
+#[test]
+fn an_idle_bot_attacks_after_a_pause_within_its_difficultys_range() {
+    for difficulty in Difficulty::ALL {
+        let timing = difficulty.timing();
+        let mut world = WorldSnapshot::default();
+        let mut bot = Bot::new(1, timing.clone(), SEED);
+        let idle = world.players[0].idle_input();
+        while world.players[1].current_attack.is_none() {
+            assert!(world.frame < 1000, "{difficulty} bot never attacked");
+            let input = bot.input(&world);
+            simulate(&mut world, [idle, input]);
+        }
+
+        let delay = FrameOffset::at_frame(0)
+            .get_offset_seconds(&world.players[1].attack_start_time, world.frame_rate);
+        assert!(
+            (timing.min_attack_delay..=timing.max_attack_delay).contains(&delay),
+            "{difficulty} bot attacked after {delay:?}"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! A checksum over [`WorldSnapshot`] that is the same on every build, platform and Rust
+//! version, unlike [`std::hash::Hash`] with `DefaultHasher`.
+//!
+//! The snapshot is fed field by field, in declaration order, into 128-bit FNV-1a:
+//!
+//! - integers are written as little-endian bytes, with `usize` widened to `u64`
+//! - an `Option` is a `0` byte for `None`, or a `1` byte followed by the value
+//! - an enum without fields is its variant index as a single byte
+//! - arrays are their elements in order, with no length prefix
+//!
+//! Changing a snapshot type means changing its [`StableHash`] impl here too.
+
+use iunorm::Unorm64;
+
+use super::{Attack, FinalClash, FrameOffset, FrameRate, GameState, Player, Second, WorldSnapshot};
+
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+pub struct Fnv1a128(u128);
+
+impl Fnv1a128 {
+    pub fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u128;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+    pub fn finish(&self) -> u128 {
+        self.0
+    }
+}
+
+pub trait StableHash {
+    fn stable_hash(&self, hasher: &mut Fnv1a128);
+}
+
+impl StableHash for u8 {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        hasher.write(&[*self]);
+    }
+}
+impl StableHash for u32 {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        hasher.write(&self.to_le_bytes());
+    }
+}
+impl StableHash for u64 {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        hasher.write(&self.to_le_bytes());
+    }
+}
+impl StableHash for i64 {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        hasher.write(&self.to_le_bytes());
+    }
+}
+impl StableHash for usize {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        (*self as u64).stable_hash(hasher);
+    }
+}
+impl StableHash for Unorm64 {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.0.stable_hash(hasher);
+    }
+}
+impl<T: StableHash> StableHash for Option<T> {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        match self {
+            None => 0u8.stable_hash(hasher),
+            Some(value) => {
+                1u8.stable_hash(hasher);
+                value.stable_hash(hasher);
+            }
+        }
+    }
+}
+impl<T: StableHash, const N: usize> StableHash for [T; N] {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        for value in self {
+            value.stable_hash(hasher);
+        }
+    }
+}
+
+impl StableHash for Second {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.0.stable_hash(hasher);
+    }
+}
+impl StableHash for FrameRate {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.ticks_per_frame.stable_hash(hasher);
+    }
+}
+impl StableHash for FrameOffset {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.frame.stable_hash(hasher);
+        self.offset.stable_hash(hasher);
+    }
+}
+impl StableHash for Attack {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.startup_time.stable_hash(hasher);
+        self.block_grace.stable_hash(hasher);
+        self.recover_time.stable_hash(hasher);
+    }
+}
+impl StableHash for Player {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.current_attack.stable_hash(hasher);
+        self.attack_start_time.stable_hash(hasher);
+        self.attack_recover_time.stable_hash(hasher);
+        self.last_defend_result.stable_hash(hasher);
+        self.stamina.stable_hash(hasher);
+        self.final_clash_lives.stable_hash(hasher);
+        self.final_clash_last_swing.stable_hash(hasher);
+        self.invalid_swings.stable_hash(hasher);
+        self.swing_count.stable_hash(hasher);
+    }
+}
+impl StableHash for FinalClash {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.next_clash.stable_hash(hasher);
+    }
+}
+impl StableHash for GameState {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        let index: u8 = match self {
+            GameState::Playing => 0,
+            GameState::FinalClash => 1,
+            GameState::Over => 2,
+        };
+        index.stable_hash(hasher);
+    }
+}
+impl StableHash for WorldSnapshot {
+    fn stable_hash(&self, hasher: &mut Fnv1a128) {
+        self.frame_rate.stable_hash(hasher);
+        self.frame.stable_hash(hasher);
+        self.players.stable_hash(hasher);
+        self.final_clash.stable_hash(hasher);
+        self.game_state.stable_hash(hasher);
+    }
+}
+
+impl WorldSnapshot {
+    /// The checksum handed to GGRS for desync detection.
+    pub fn checksum(&self) -> u128 {
+        let mut hasher = Fnv1a128::new();
+        self.stable_hash(&mut hasher);
+        hasher.finish()
+    }
+}
@@ -0,0 +1,121 @@
+//! Binary and human-readable encodings of the simulation types.
+//!
+//! Both encodings carry [`FORMAT_VERSION`] so that files written by one build can be
+//! rejected cleanly by another that doesn't understand them.
+//!
+//! The binary encoding is the 4-byte magic `CATK`, the version as a little-endian `u16`,
+//! then the value in bincode's default layout: little-endian fixed-width integers, with
+//! `usize` as `u64`. The readable encoding is pretty-printed RON of
+//! `(version: 4, value: ...)`.
+
+use std::{error::Error, fmt};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Bump whenever a serialized type changes shape.
+pub const FORMAT_VERSION: u16 = 4;
+const MAGIC: &[u8; 4] = b"CATK";
+
+#[derive(Debug)]
+pub enum FormatError {
+    /// The data doesn't start with the binary magic.
+    NotBinarySnapshot,
+    UnsupportedVersion(u16),
+    Binary(bincode::Error),
+    Readable(ron::error::SpannedError),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::NotBinarySnapshot => write!(f, "not a binary snapshot"),
+            FormatError::UnsupportedVersion(version) => write!(
+                f,
+                "format version {version} is not supported, expected {FORMAT_VERSION}"
+            ),
+            FormatError::Binary(err) => write!(f, "invalid binary snapshot: {err}"),
+            FormatError::Readable(err) => write!(f, "invalid snapshot: {err}"),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    version: u16,
+    value: &'a T,
+}
+
+/// The value, once [`VersionHeader`] has been checked.
+#[derive(Deserialize)]
+struct VersionedValue<T> {
+    value: T,
+}
+
+/// Only the version, so it can be checked before the rest is parsed.
+#[derive(Deserialize)]
+struct VersionHeader {
+    version: u16,
+}
+
+fn check_version(version: u16) -> Result<(), FormatError> {
+    if version == FORMAT_VERSION {
+        Ok(())
+    } else {
+        Err(FormatError::UnsupportedVersion(version))
+    }
+}
+
+pub fn to_binary<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, value).expect("simulation types always serialize");
+    bytes
+}
+
+pub fn from_binary<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, FormatError> {
+    let body = bytes
+        .strip_prefix(MAGIC)
+        .ok_or(FormatError::NotBinarySnapshot)?;
+    let (version, body) = body
+        .split_first_chunk::<2>()
+        .ok_or(FormatError::NotBinarySnapshot)?;
+    check_version(u16::from_le_bytes(*version))?;
+    bincode::deserialize(body).map_err(FormatError::Binary)
+}
+
+pub fn to_readable<T: Serialize>(value: &T) -> String {
+    ron::ser::to_string_pretty(
+        &Versioned {
+            version: FORMAT_VERSION,
+            value,
+        },
+        ron::ser::PrettyConfig::default(),
+    )
+    .expect("simulation types always serialize")
+}
+
+pub fn from_readable<T: DeserializeOwned>(text: &str) -> Result<T, FormatError> {
+    let header: VersionHeader = ron::from_str(text).map_err(FormatError::Readable)?;
+    check_version(header.version)?;
+    let versioned: VersionedValue<T> = ron::from_str(text).map_err(FormatError::Readable)?;
+    Ok(versioned.value)
+}
+
+/// Serializes an [`iunorm::Unorm64`] as its raw `u64`.
+pub(super) mod unorm64 {
+    use iunorm::Unorm64;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Unorm64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(value.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Unorm64, D::Error> {
+        u64::deserialize(deserializer).map(Unorm64)
+    }
+}
+
+#[cfg(test)]
+mod tests;
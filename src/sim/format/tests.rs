@@ -0,0 +1,102 @@
+//! Both encodings, round-tripped and fed data they should turn away.
+
+use super::*;
+use crate::sim::{replay::Replay, simulate, FrameOffset, FrameRate, Second, WorldSnapshot};
+
+/// A match 40 frames in, with player 1 having blocked player 0's attack and their own in
+/// flight, and the replay of how it got there with player 1 leaving at the end.
+fn mid_match() -> (WorldSnapshot, Replay) {
+    let frame_rate = FrameRate::from_fps(30).unwrap();
+    let mut world = WorldSnapshot::new(frame_rate);
+    let mut replay = Replay::new(frame_rate);
+    while world.frame < 40 {
+        let mut inputs = world.players.clone().map(|player| player.idle_input());
+        let swinging = match world.frame {
+            0 => Some(0),
+            20 => Some(1),
+            _ => None,
+        };
+        if let Some(handle) = swinging {
+            let frame = world.frame;
+            inputs[handle] = inputs[handle].swung(FrameOffset {
+                frame,
+                offset: 1234,
+            });
+        }
+        simulate(&mut world, inputs);
+        replay.inputs.push(inputs);
+    }
+    replay.departures.push((40, 1));
+    (world, replay)
+}
+
+fn assert_same_snapshot(decoded: &WorldSnapshot, original: &WorldSnapshot) {
+    assert_eq!(decoded.checksum(), original.checksum());
+    assert_eq!(to_binary(decoded), to_binary(original));
+}
+
+fn assert_same_replay(decoded: &Replay, original: &Replay) {
+    assert_eq!(decoded.rules, original.rules);
+    assert_eq!(decoded.inputs, original.inputs);
+    assert_eq!(decoded.departures, original.departures);
+}
+
+#[test]
+fn a_mid_match_snapshot_round_trips_through_both_encodings() {
+    let (world, _) = mid_match();
+    assert!(world.players[1].current_attack.is_some());
+    assert_ne!(world.players[1].last_defend_result, Second::ZERO);
+
+    let binary: WorldSnapshot = from_binary(&to_binary(&world)).unwrap();
+    assert_same_snapshot(&binary, &world);
+    let readable: WorldSnapshot = from_readable(&to_readable(&world)).unwrap();
+    assert_same_snapshot(&readable, &world);
+}
+
+#[test]
+fn a_replay_round_trips_through_both_encodings() {
+    let (_, replay) = mid_match();
+
+    let binary: Replay = from_binary(&to_binary(&replay)).unwrap();
+    assert_same_replay(&binary, &replay);
+    let readable: Replay = from_readable(&to_readable(&replay)).unwrap();
+    assert_same_replay(&readable, &replay);
+}
+
+#[test]
+fn binary_data_with_the_wrong_magic_is_rejected() {
+    let (world, _) = mid_match();
+    let mut bytes = to_binary(&world);
+    bytes[..4].copy_from_slice(b"CATX");
+
+    let result = from_binary::<WorldSnapshot>(&bytes);
+    assert!(
+        matches!(result, Err(FormatError::NotBinarySnapshot)),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn other_format_versions_are_rejected() {
+    let (world, _) = mid_match();
+    for version in [FORMAT_VERSION - 1, FORMAT_VERSION + 1] {
+        let mut bytes = to_binary(&world);
+        bytes[4..6].copy_from_slice(&version.to_le_bytes());
+        let result = from_binary::<WorldSnapshot>(&bytes);
+        assert!(
+            matches!(result, Err(FormatError::UnsupportedVersion(v)) if v == version),
+            "{result:?}"
+        );
+
+        let text = to_readable(&world).replacen(
+            &format!("version: {FORMAT_VERSION}"),
+            &format!("version: {version}"),
+            1,
+        );
+        let result = from_readable::<WorldSnapshot>(&text);
+        assert!(
+            matches!(result, Err(FormatError::UnsupportedVersion(v)) if v == version),
+            "{result:?}"
+        );
+    }
+}
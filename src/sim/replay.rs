@@ -0,0 +1,75 @@
+//! A record of a match: the confirmed inputs of every frame, who left it and when, and the
+//! rules it was played under.
+
+use ggrs::PlayerHandle;
+use iunorm::Unorm64;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    format, Attack, FrameRate, Second, SendInput, BASE_STAMINA_LOSS, CLASH_LENGTH,
+    FINAL_CLASH_LIVES, TEST_ATTACK,
+};
+
+/// The constants that decide how a match plays out. A replay only plays back the same way
+/// under the rules it was recorded with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rules {
+    pub test_attack: Attack,
+    pub final_clash_lives: u8,
+    pub clash_length: Second,
+    #[serde(with = "format::unorm64")]
+    pub base_stamina_loss: Unorm64,
+    pub frametime: Second,
+}
+
+impl Rules {
+    /// The rules this build plays by at `frame_rate`.
+    pub fn current(frame_rate: FrameRate) -> Self {
+        Self {
+            test_attack: TEST_ATTACK,
+            final_clash_lives: FINAL_CLASH_LIVES,
+            clash_length: CLASH_LENGTH,
+            base_stamina_loss: BASE_STAMINA_LOSS,
+            frametime: frame_rate.frametime(),
+        }
+    }
+    /// The frame rate the rules were played at, unless their frame time isn't one this
+    /// build can run at.
+    pub fn frame_rate(&self) -> Option<FrameRate> {
+        FrameRate::from_frametime(self.frametime)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Replay {
+    pub rules: Rules,
+    /// The inputs for frame 1 onwards, indexed by player handle.
+    pub inputs: Vec<[SendInput; 2]>,
+    /// The first frame each player who left was gone for, with their handle. They
+    /// [`forfeit`](super::forfeit) from then on.
+    pub departures: Vec<(usize, PlayerHandle)>,
+}
+
+impl Replay {
+    pub fn new(frame_rate: FrameRate) -> Self {
+        Self {
+            rules: Rules::current(frame_rate),
+            inputs: vec![],
+            departures: vec![],
+        }
+    }
+    /// The input `handle` gave for simulating `frame`, or their last one, which doesn't
+    /// swing again, once the recording has run out.
+    pub fn input(&self, frame: usize, handle: PlayerHandle) -> SendInput {
+        frame
+            .checked_sub(1)
+            .and_then(|index| self.inputs.get(index).or(self.inputs.last()))
+            .map_or(SendInput::default(), |inputs| inputs[handle])
+    }
+    /// Whether `handle` had left by the time `frame` was simulated.
+    pub fn has_left(&self, frame: usize, handle: PlayerHandle) -> bool {
+        self.departures
+            .iter()
+            .any(|&(left, departed)| departed == handle && left <= frame)
+    }
+}
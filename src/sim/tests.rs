@@ -0,0 +1,321 @@
+//! The duel rules, played out frame by frame with hand-picked swings.
+
+use super::*;
+
+/// Simulates the next frame, with a swing `offset` ticks into it for each player given one.
+fn step(world: &mut WorldSnapshot, swings: [Option<u64>; 2]) -> Vec<SimEvent> {
+    let frame = world.frame;
+    let inputs = [0, 1].map(|handle| {
+        let idle = world.players[handle].idle_input();
+        match swings[handle] {
+            Some(offset) => idle.swung(FrameOffset { frame, offset }),
+            None => idle,
+        }
+    });
+    simulate(world, inputs)
+}
+
+/// Simulates frames without swings until `world.frame` is `frame`, returning their events.
+fn idle_until(world: &mut WorldSnapshot, frame: usize) -> Vec<SimEvent> {
+    let mut events = vec![];
+    while world.frame < frame {
+        events.extend(step(world, [None, None]));
+    }
+    events
+}
+
+/// The frame whose simulation covers `at`.
+fn frame_covering(at: FrameOffset) -> usize {
+    at.frame + 1
+}
+
+fn impact(world: &WorldSnapshot, handle: PlayerHandle) -> FrameOffset {
+    let player = &world.players[handle];
+    let attack = player.current_attack.as_ref().unwrap();
+    player
+        .attack_start_time
+        .after(attack.startup_time, world.frame_rate)
+}
+
+#[test]
+fn a_swing_on_impact_blocks_the_attack_for_a_fifth_of_the_stamina() {
+    let mut world = WorldSnapshot::default();
+    assert!(step(&mut world, [Some(0), None]).is_empty());
+    let impact = impact(&world, 0);
+
+    idle_until(&mut world, frame_covering(impact) - 1);
+    let events = step(&mut world, [None, Some(impact.offset)]);
+
+    assert_eq!(
+        events,
+        [SimEvent::Block {
+            handle: 1,
+            quality: 1.0
+        }]
+    );
+    assert!(world.players[0].current_attack.is_none());
+    assert_eq!(world.players[1].last_defend_result, Second::ZERO);
+    assert_eq!(world.players[0].stamina, Unorm64(u64::MAX));
+    assert_eq!(
+        world.players[1].stamina,
+        Unorm64(u64::MAX - BASE_STAMINA_LOSS.0 / 5)
+    );
+}
+
+#[test]
+fn a_late_block_costs_more_stamina() {
+    let mut world = WorldSnapshot::default();
+    step(&mut world, [Some(0), None]);
+    let late = impact(&world, 0).after(Second::from_millis(250), world.frame_rate);
+
+    idle_until(&mut world, frame_covering(late) - 1);
+    let events = step(&mut world, [None, Some(late.offset)]);
+
+    let [SimEvent::Block { handle: 1, quality }] = events[..] else {
+        panic!("expected a block, got {events:?}");
+    };
+    assert_eq!(quality, 0.75);
+    assert_eq!(
+        world.players[1].last_defend_result,
+        -Second::from_millis(250)
+    );
+    // A quarter of a second off costs a fifth more than a perfect block.
+    let loss = BASE_STAMINA_LOSS.0 as u128 * 2 / 5;
+    assert_eq!(world.players[1].stamina.0, u64::MAX - loss as u64);
+}
+
+#[test]
+fn an_unblocked_attack_lands_once_the_block_grace_is_over() {
+    let mut world = WorldSnapshot::default();
+    step(&mut world, [Some(0), None]);
+    let grace_over = impact(&world, 0).after(TEST_ATTACK.block_grace, world.frame_rate);
+
+    idle_until(&mut world, grace_over.frame);
+    assert_eq!(world.players[1].stamina, Unorm64(u64::MAX));
+    assert!(world.players[0].current_attack.is_some());
+
+    step(&mut world, [None, None]);
+    assert_eq!(
+        world.players[1].stamina,
+        Unorm64(u64::MAX - BASE_STAMINA_LOSS.0 / 2 * 3)
+    );
+    assert!(world.players[0].current_attack.is_none());
+}
+
+#[test]
+fn the_final_clash_begins_once_both_players_are_out_of_stamina() {
+    let mut world = WorldSnapshot::default();
+    world.players[0].stamina = Unorm64(0);
+    world.players[1].stamina = Unorm64(1);
+    step(&mut world, [Some(0), None]);
+
+    let events = idle_until(&mut world, 100);
+
+    assert_eq!(events, [SimEvent::FinalClashBegan]);
+    assert_eq!(world.game_state, GameState::FinalClash);
+    assert_eq!(world.players[1].stamina, Unorm64(0));
+}
+
+#[test]
+fn a_hit_with_no_stamina_left_loses_the_match() {
+    let mut world = WorldSnapshot::default();
+    world.players[1].stamina = Unorm64(0);
+    step(&mut world, [Some(0), None]);
+
+    let events = idle_until(&mut world, 100);
+
+    assert_eq!(events, [SimEvent::GameOver { loser: Some(1) }]);
+    assert_eq!(world.game_state, GameState::Over);
+}
+
+/// A match that has just entered the final clash, with the first round about to start.
+fn final_clash() -> WorldSnapshot {
+    WorldSnapshot {
+        game_state: GameState::FinalClash,
+        ..WorldSnapshot::default()
+    }
+}
+
+#[test]
+fn the_swing_closer_to_the_clash_wins_a_parry() {
+    let mut world = final_clash();
+    step(&mut world, [None, None]);
+    let next_clash = world.final_clash.next_clash.unwrap();
+    let rate = world.frame_rate;
+    let early = next_clash.after(-Second::from_millis(100), rate);
+    let late = next_clash.after(Second::from_millis(50), rate);
+
+    idle_until(&mut world, frame_covering(early) - 1);
+    step(&mut world, [Some(early.offset), None]);
+    idle_until(&mut world, frame_covering(late) - 1);
+    let events = step(&mut world, [None, Some(late.offset)]);
+
+    assert_eq!(events, [SimEvent::FinalClashParry]);
+    let lives = world
+        .players
+        .each_ref()
+        .map(|player| player.final_clash_lives);
+    assert_eq!(lives, [FINAL_CLASH_LIVES - 1, FINAL_CLASH_LIVES]);
+    assert_eq!(world.final_clash.next_clash, None);
+    assert_eq!(world.game_state, GameState::FinalClash);
+}
+
+#[test]
+fn a_player_who_doesnt_swing_in_a_final_clash_round_is_cut() {
+    let mut world = final_clash();
+    step(&mut world, [None, None]);
+    let next_clash = world.final_clash.next_clash.unwrap();
+    step(&mut world, [Some(0), None]);
+
+    let round_over = next_clash.after(CLASH_LENGTH, world.frame_rate);
+    let events = idle_until(&mut world, frame_covering(round_over));
+
+    assert_eq!(events, [SimEvent::FinalClashCut { handle: 1 }]);
+    let lives = world
+        .players
+        .each_ref()
+        .map(|player| player.final_clash_lives);
+    assert_eq!(lives, [FINAL_CLASH_LIVES, FINAL_CLASH_LIVES - 1]);
+}
+
+#[test]
+fn losing_the_last_lives_together_is_a_tie() {
+    let mut world = final_clash();
+    for player in &mut world.players {
+        player.final_clash_lives = 1;
+    }
+
+    let events = idle_until(&mut world, 200);
+
+    assert_eq!(events, [SimEvent::GameOver { loser: None }]);
+    assert_eq!(world.game_state, GameState::Over);
+}
+
+#[test]
+fn losing_the_last_final_clash_life_loses_the_match() {
+    let mut world = final_clash();
+    world.players[0].final_clash_lives = 1;
+    step(&mut world, [None, None]);
+    step(&mut world, [None, Some(0)]);
+
+    let events = idle_until(&mut world, 200);
+
+    assert_eq!(
+        events,
+        [
+            SimEvent::FinalClashCut { handle: 0 },
+            SimEvent::GameOver { loser: Some(0) }
+        ]
+    );
+    assert_eq!(world.game_state, GameState::Over);
+}
+
+#[test]
+fn matches_at_different_frame_rates_keep_the_same_time() {
+    // The attack lands on the first frame to start more than 1.2s after it did.
+    for (fps, hit_frame) in [(30, 37), (60, 73)] {
+        let mut world = WorldSnapshot::new(FrameRate::from_fps(fps).unwrap());
+        step(&mut world, [Some(0), None]);
+        idle_until(&mut world, hit_frame - 1);
+        assert_eq!(world.players[1].stamina, Unorm64(u64::MAX), "at {fps} fps");
+        step(&mut world, [None, None]);
+        assert!(world.players[1].stamina < Unorm64(u64::MAX), "at {fps} fps");
+    }
+}
+
+#[test]
+fn moving_back_past_the_start_of_a_frame_borrows_from_the_frames_before() {
+    let rate = FrameRate::from_fps(60).unwrap();
+    let ticks_per_frame = rate.ticks_per_frame();
+    let at = FrameOffset {
+        frame: 10,
+        offset: 100,
+    };
+
+    assert_eq!(at.after(Second(-100), rate), FrameOffset::at_frame(10));
+    assert_eq!(
+        at.after(Second(-101), rate),
+        FrameOffset {
+            frame: 9,
+            offset: ticks_per_frame as u64 - 1
+        }
+    );
+    assert_eq!(
+        at.after(Second(-100 - 2 * ticks_per_frame), rate),
+        FrameOffset::at_frame(8)
+    );
+    assert_eq!(
+        at.after(Second(-101 - 2 * ticks_per_frame), rate),
+        FrameOffset {
+            frame: 7,
+            offset: ticks_per_frame as u64 - 1
+        }
+    );
+}
+
+#[test]
+fn moving_by_any_span_keeps_the_offset_within_a_frame() {
+    for fps in [10, 60] {
+        let rate = FrameRate::from_fps(fps).unwrap();
+        let ticks_per_frame = rate.ticks_per_frame();
+        let start = FrameOffset {
+            frame: 100,
+            offset: 7,
+        };
+        for ticks in -2 * ticks_per_frame..=2 * ticks_per_frame {
+            let moved = start.after(Second(ticks), rate);
+            assert!(
+                moved.offset < ticks_per_frame as u64,
+                "{ticks} ticks at {fps} fps gave {moved:?}"
+            );
+            assert_eq!(
+                start.get_offset_seconds(&moved, rate),
+                Second(ticks),
+                "at {fps} fps"
+            );
+        }
+    }
+}
+
+#[test]
+fn spans_cover_the_same_time_at_10_and_60_hz() {
+    let span = Second::from_millis(250);
+
+    // 72,000 ticks a frame, so a quarter second is two and a half frames.
+    let rate = FrameRate::from_fps(10).unwrap();
+    let at = FrameOffset {
+        frame: 4,
+        offset: 40_000,
+    };
+    let later = FrameOffset {
+        frame: 7,
+        offset: 4_000,
+    };
+    let earlier = FrameOffset {
+        frame: 2,
+        offset: 4_000,
+    };
+    assert_eq!(at.after(span, rate), later);
+    assert_eq!(at.after(-span, rate), earlier);
+    assert_eq!(at.get_offset_seconds(&later, rate), span);
+    assert_eq!(at.get_offset_seconds(&earlier, rate), -span);
+
+    // 12,000 ticks a frame, so a quarter second is fifteen frames.
+    let rate = FrameRate::from_fps(60).unwrap();
+    let at = FrameOffset {
+        frame: 20,
+        offset: 5_000,
+    };
+    let later = FrameOffset {
+        frame: 35,
+        offset: 5_000,
+    };
+    let earlier = FrameOffset {
+        frame: 5,
+        offset: 5_000,
+    };
+    assert_eq!(at.after(span, rate), later);
+    assert_eq!(at.after(-span, rate), earlier);
+    assert_eq!(at.get_offset_seconds(&later, rate), span);
+    assert_eq!(at.get_offset_seconds(&earlier, rate), -span);
+}
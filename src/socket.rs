@@ -0,0 +1,381 @@
+//! The socket GGRS talks through, and the transports that carry its datagrams.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+};
+
+use counter_attack::{
+    lobby::{self, LobbyReply, LobbyRequest},
+    relay,
+    sim::{
+        format::{self, FormatError},
+        replay::Rules,
+    },
+};
+use ggrs::{Frame, Message, NonBlockingSocket};
+
+use crate::auth::RejectedPackets;
+
+pub const RECV_BUFFER_SIZE: usize = 4096;
+/// Latency probes are the tag followed by a little-endian `u64` nonce. Neither tag, nor
+/// [`RULES`], [`RULES_REPLY`], [`CHECKSUM`], [`CHECKSUM_ACK`] or [`lobby::TAG`], can start
+/// a GGRS message, whose body tag after the 2-byte magic is a small integer.
+const PING: &[u8; 4] = b"PING";
+const PONG: &[u8; 4] = b"PONG";
+/// The tag followed by the sender's [`Rules`] in the binary [`format`], sent by a player
+/// waiting to start a session with the receiver.
+const RULES: &[u8; 4] = b"RULE";
+/// The tag followed by the sender's [`Rules`], in answer to [`RULES`].
+const RULES_REPLY: &[u8; 4] = b"RULR";
+/// The tag followed by a confirmed frame as a little-endian `i32`, and the checksum of its
+/// snapshot as a little-endian `u128`.
+const CHECKSUM: &[u8; 4] = b"CSUM";
+/// The tag followed by the frame of a checksum received, as a little-endian `i32`.
+const CHECKSUM_ACK: &[u8; 4] = b"CACK";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumMessage {
+    Checksum {
+        frame: Frame,
+        checksum: u128,
+    },
+    /// The checksum for `frame` arrived.
+    Ack {
+        frame: Frame,
+    },
+}
+
+impl ChecksumMessage {
+    fn encode(self) -> Vec<u8> {
+        let mut datagram = vec![];
+        match self {
+            ChecksumMessage::Checksum { frame, checksum } => {
+                datagram.extend_from_slice(CHECKSUM);
+                datagram.extend_from_slice(&frame.to_le_bytes());
+                datagram.extend_from_slice(&checksum.to_le_bytes());
+            }
+            ChecksumMessage::Ack { frame } => {
+                datagram.extend_from_slice(CHECKSUM_ACK);
+                datagram.extend_from_slice(&frame.to_le_bytes());
+            }
+        }
+        datagram
+    }
+    fn decode(datagram: &[u8]) -> Option<Self> {
+        let (tag, body) = datagram.split_first_chunk::<4>()?;
+        let (frame, rest) = body.split_first_chunk::<4>()?;
+        let frame = Frame::from_le_bytes(*frame);
+        match (tag, rest.len()) {
+            (CHECKSUM, 16) => Some(ChecksumMessage::Checksum {
+                frame,
+                checksum: u128::from_le_bytes(rest.try_into().ok()?),
+            }),
+            (CHECKSUM_ACK, 0) => Some(ChecksumMessage::Ack { frame }),
+            _ => None,
+        }
+    }
+}
+
+/// Messages about the checksums of confirmed frames, passed between a [`GameSocket`] and
+/// whoever compares them, since GGRS owns the socket once its session starts.
+#[derive(Default)]
+pub struct Checksums {
+    /// To send on the socket's next poll.
+    pub outgoing: Vec<(SocketAddr, ChecksumMessage)>,
+    /// Received since they were last taken.
+    pub incoming: Vec<(SocketAddr, ChecksumMessage)>,
+}
+
+/// Carries a [`GameSocket`]'s datagrams.
+pub trait Transport: Send + Sync {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>;
+    /// Reads the next datagram into `buffer`, failing with [`ErrorKind::WouldBlock`] once
+    /// there are none left.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, datagram, addr).map(|_| ())
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        (**self).send_to(datagram, addr)
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buffer)
+    }
+}
+
+/// A non-blocking UDP socket on `port`, on every interface of the same IP version as
+/// `peer_addr`.
+pub fn bind_udp(port: u16, peer_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = match peer_addr {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))?,
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Tunnels everything addressed to the relay server at `relay_addr` through it, as
+/// session `session_id`, so that the other player's datagrams seem to come from the relay.
+/// Anything addressed elsewhere, such as to spectators, goes directly.
+pub struct RelayTransport<T> {
+    inner: T,
+    relay_addr: SocketAddr,
+    session_id: u64,
+}
+
+impl<T: Transport> RelayTransport<T> {
+    pub fn new(inner: T, relay_addr: SocketAddr, session_id: u64) -> Self {
+        Self {
+            inner,
+            relay_addr,
+            session_id,
+        }
+    }
+}
+
+impl<T: Transport> Transport for RelayTransport<T> {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if addr == self.relay_addr {
+            self.inner
+                .send_to(&relay::wrap(self.session_id, datagram), addr)
+        } else {
+            self.inner.send_to(datagram, addr)
+        }
+    }
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (number_of_bytes, src_addr) = self.inner.recv_from(buffer)?;
+            if src_addr != self.relay_addr {
+                return Ok((number_of_bytes, src_addr));
+            }
+            match relay::unwrap(&buffer[..number_of_bytes]) {
+                Some((session_id, payload)) if session_id == self.session_id => {
+                    let payload_length = payload.len();
+                    buffer.copy_within(relay::HEADER_LENGTH..number_of_bytes, 0);
+                    return Ok((payload_length, src_addr));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Like [`ggrs::UdpNonBlockingSocket`], but reports network errors instead of panicking on
+/// them, so an unreachable peer shows up as a dropped connection.
+pub struct GameSocket {
+    transport: Box<dyn Transport>,
+    buffer: [u8; RECV_BUFFER_SIZE],
+    /// Only the first of a run of identical errors is printed.
+    last_error: Option<ErrorKind>,
+    /// Answers to [`GameSocket::ping`] received since they were last taken.
+    pongs: Vec<(SocketAddr, u64)>,
+    /// Replies from a lobby server received since they were last taken.
+    lobby_replies: Vec<(SocketAddr, LobbyReply)>,
+    /// This player's rules, to answer another player's with.
+    rules: Option<Rules>,
+    /// Other players' rules received since they were last taken, or why they couldn't be
+    /// read.
+    peer_rules: Vec<(SocketAddr, Result<Rules, FormatError>)>,
+    /// Messages received while only looking for pongs, rules or lobby replies, kept for the
+    /// session.
+    unread: Vec<(SocketAddr, Message)>,
+    checksums: Arc<Mutex<Checksums>>,
+    /// Counted by the transport, if it checks signatures.
+    rejected_packets: Arc<Mutex<RejectedPackets>>,
+}
+
+impl GameSocket {
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            buffer: [0; RECV_BUFFER_SIZE],
+            last_error: None,
+            pongs: vec![],
+            lobby_replies: vec![],
+            rules: None,
+            peer_rules: vec![],
+            unread: vec![],
+            checksums: Arc::default(),
+            rejected_packets: Arc::default(),
+        }
+    }
+
+    /// Answers the rules other players send with `rules`, whatever state its session is in.
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+    /// Reports the counts from an [`AuthTransport`](crate::auth::AuthTransport) somewhere
+    /// in the transport.
+    pub fn with_rejected_packets(mut self, rejected_packets: Arc<Mutex<RejectedPackets>>) -> Self {
+        self.rejected_packets = rejected_packets;
+        self
+    }
+    /// The datagrams the transport has dropped, readable once the socket has been handed
+    /// to GGRS.
+    pub fn rejected_packets(&self) -> Arc<Mutex<RejectedPackets>> {
+        self.rejected_packets.clone()
+    }
+
+    /// Where to leave checksums to send, and find those received, once the socket has been
+    /// handed to GGRS.
+    pub fn checksums(&self) -> Arc<Mutex<Checksums>> {
+        self.checksums.clone()
+    }
+
+    /// Sends a latency probe to `addr`. Every `GameSocket` answers probes by itself while
+    /// receiving messages, whatever state its session is in.
+    pub fn ping(&mut self, addr: SocketAddr, nonce: u64) {
+        self.send_probe(PING, nonce, addr);
+    }
+    /// The addresses and nonces of the pings answered since this was last called. Any
+    /// messages received meanwhile are returned by the next
+    /// [`receive_all_messages`](NonBlockingSocket::receive_all_messages), as GGRS doesn't
+    /// always resend those sent before its session started.
+    pub fn receive_pongs(&mut self) -> Vec<(SocketAddr, u64)> {
+        self.receive();
+        mem::take(&mut self.pongs)
+    }
+
+    /// Sends this player's rules to `addr`, who answers with theirs.
+    pub fn send_rules(&mut self, addr: SocketAddr) {
+        self.send_rules_message(RULES, addr);
+    }
+    /// The rules other players have sent or answered with since this was last called. Like
+    /// [`GameSocket::receive_pongs`], this keeps other messages for the session.
+    pub fn receive_rules(&mut self) -> Vec<(SocketAddr, Result<Rules, FormatError>)> {
+        self.receive();
+        mem::take(&mut self.peer_rules)
+    }
+
+    pub fn send_to_lobby(&mut self, addr: SocketAddr, request: &LobbyRequest) {
+        let result = self.transport.send_to(&lobby::encode(request), addr);
+        self.report(result, format_args!("sending to {addr}"));
+    }
+    /// The lobby replies received since this was last called. Like
+    /// [`GameSocket::receive_pongs`], this keeps other messages for the session.
+    pub fn receive_lobby_replies(&mut self) -> Vec<(SocketAddr, LobbyReply)> {
+        self.receive();
+        mem::take(&mut self.lobby_replies)
+    }
+
+    fn receive(&mut self) {
+        loop {
+            match self.transport.recv_from(&mut self.buffer) {
+                Ok((number_of_bytes, src_addr)) => {
+                    let datagram = &self.buffer[..number_of_bytes];
+                    if let Some(nonce) = probe_nonce(datagram, PING) {
+                        self.send_probe(PONG, nonce, src_addr);
+                    } else if let Some(nonce) = probe_nonce(datagram, PONG) {
+                        self.pongs.push((src_addr, nonce));
+                    } else if let Some((is_query, rules)) = rules_message(datagram) {
+                        if is_query {
+                            self.send_rules_message(RULES_REPLY, src_addr);
+                        }
+                        self.peer_rules.push((src_addr, rules));
+                    } else if datagram.starts_with(CHECKSUM) || datagram.starts_with(CHECKSUM_ACK) {
+                        if let Some(message) = ChecksumMessage::decode(datagram) {
+                            let mut checksums = self.checksums.lock().unwrap();
+                            checksums.incoming.push((src_addr, message));
+                        }
+                    } else if datagram.starts_with(lobby::TAG) {
+                        if let Some(reply) = lobby::decode(datagram) {
+                            self.lobby_replies.push((src_addr, reply));
+                        }
+                    } else if let Ok(msg) = bincode::deserialize(datagram) {
+                        self.unread.push((src_addr, msg));
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                // An earlier send bounced off a closed port.
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    self.report(Err(err), format_args!("receiving"));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send_probe(&mut self, tag: &[u8; 4], nonce: u64, addr: SocketAddr) {
+        let mut buf = [0; 12];
+        buf[..4].copy_from_slice(tag);
+        buf[4..].copy_from_slice(&nonce.to_le_bytes());
+        let result = self.transport.send_to(&buf, addr);
+        self.report(result, format_args!("sending to {addr}"));
+    }
+
+    fn send_rules_message(&mut self, tag: &[u8; 4], addr: SocketAddr) {
+        let Some(rules) = &self.rules else {
+            return;
+        };
+        let mut datagram = tag.to_vec();
+        datagram.extend_from_slice(&format::to_binary(rules));
+        let result = self.transport.send_to(&datagram, addr);
+        self.report(result, format_args!("sending to {addr}"));
+    }
+
+    fn report(&mut self, result: io::Result<()>, action: fmt::Arguments) {
+        match result {
+            Ok(()) => self.last_error = None,
+            Err(err) if self.last_error != Some(err.kind()) => {
+                eprintln!("Network error while {action}: {err}");
+                self.last_error = Some(err.kind());
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl NonBlockingSocket<SocketAddr> for GameSocket {
+    fn send_to(&mut self, msg: &Message, addr: &SocketAddr) {
+        let buf = bincode::serialize(msg).unwrap();
+        let result = self.transport.send_to(&buf, *addr);
+        self.report(result, format_args!("sending to {addr}"));
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(SocketAddr, Message)> {
+        // GGRS polls the socket every frame, so this is as good a time as any to send.
+        let outgoing = mem::take(&mut self.checksums.lock().unwrap().outgoing);
+        for (addr, message) in outgoing {
+            let result = self.transport.send_to(&message.encode(), addr);
+            self.report(result, format_args!("sending to {addr}"));
+        }
+        self.receive();
+        mem::take(&mut self.unread)
+    }
+}
+
+fn probe_nonce(datagram: &[u8], tag: &[u8; 4]) -> Option<u64> {
+    match datagram.split_first_chunk::<4>() {
+        Some((datagram_tag, nonce)) if datagram_tag == tag => {
+            Some(u64::from_le_bytes(nonce.try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `datagram` is [`RULES`] rather than [`RULES_REPLY`], and the rules it carries,
+/// if it is either.
+fn rules_message(datagram: &[u8]) -> Option<(bool, Result<Rules, FormatError>)> {
+    let (tag, body) = datagram.split_first_chunk::<4>()?;
+    let is_query = match tag {
+        RULES => true,
+        RULES_REPLY => false,
+        _ => return None,
+    };
+    Some((is_query, format::from_binary(body)))
+}
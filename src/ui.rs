@@ -1,7 +1,16 @@
+// The HUD predates running clippy on the game. Its style lints are allowed here rather
+// than fixed in passing by unrelated changes.
+#![allow(clippy::needless_update, clippy::upper_case_acronyms)]
+
 use bevy::{prelude::{BackgroundColor, *}, core_pipeline::bloom::BloomSettings};
-use iunorm::Inorm64;
 
-use crate::{BlockEvent, FrameOffset, LastTickTime, LocalMarker, Player, FinalClashLives, GameState, FinalClash, AssetLoadingState};
+use crate::{
+    session::SessionEvent,
+    sim::{FinalClash, GameState, Player},
+    AssetLoadingState, BlockEvent, ConnectionStatus, FinalClashLives, LastTickTime, LocalMarker,
+    NetStats, PlayerId,
+};
+use ggrs::GGRSEvent;
 
 #[derive(Component)]
 struct MovingCaret;
@@ -10,9 +19,18 @@ struct MovingCaret;
 struct StaminaBar;
 
 #[derive(Component)]
-pub struct BlockQualityIndicator;
+pub struct BlockQualityIndicator {
+    /// The frame of the block currently shown.
+    frame: Option<usize>,
+}
 #[derive(Component)]
 pub struct GameStateViewer;
+#[derive(Component)]
+struct ConnectionStatusText;
+#[derive(Component)]
+struct LagIndicator;
+#[derive(Component)]
+struct NetStatsPanel;
 
 #[derive(Resource)]
 pub struct Roboto(pub Handle<Font>);
@@ -30,6 +48,9 @@ impl Plugin for GUI {
             setup_block_quality,
             setup_final_clash_lives,
             setup_state_viewer,
+            setup_connection_status,
+            setup_lag_indicator,
+            setup_net_stats_panel,
         ).in_schedule(OnEnter(AssetLoadingState::Done)))
         .add_systems((
             handle_block_event,
@@ -42,6 +63,10 @@ impl Plugin for GUI {
             update_remote_final_clash_lives.run_if(resource_exists_and_equals(GameState::FinalClash)),
             move_caret_final_clash.run_if(resource_exists_and_equals(GameState::FinalClash)),
             update_state_viewer,
+            update_connection_status.run_if(resource_changed::<ConnectionStatus>()),
+            update_lag_indicator,
+            toggle_net_stats_panel,
+            update_net_stats_panel.run_if(resource_changed::<NetStats>()),
         ).distributive_run_if(in_state(AssetLoadingState::Done)));
     }
 }
@@ -78,7 +103,7 @@ fn setup_block_quality(mut commands: Commands, roboto: Res<Roboto>) {
             },
             ..Default::default()
         })
-        .insert(BlockQualityIndicator);
+        .insert(BlockQualityIndicator { frame: None });
 }
 
 fn update_block_quality(
@@ -92,13 +117,28 @@ fn update_block_quality(
 
 fn handle_block_event(
     mut ev_block: EventReader<BlockEvent>,
-    mut text_query: Query<&mut Text, With<BlockQualityIndicator>>,
+    mut text_query: Query<(&mut Text, &mut BlockQualityIndicator)>,
+    local_player: Query<&PlayerId, With<LocalMarker>>,
 ) {
-    let mut text = text_query.single_mut();
+    let (mut text, mut indicator) = text_query.single_mut();
+    let local_id = local_player.single();
 
     for event in ev_block.into_iter() {
-        text.sections[0].style.color.set_a(1.0);
-        text.sections[0].value = event.0.clone();
+        match *event {
+            BlockEvent::Blocked { frame, handle, text: block_text } if handle == local_id.0 => {
+                text.sections[0].style.color.set_a(1.0);
+                text.sections[0].value = block_text.into();
+                indicator.frame = Some(frame);
+            }
+            BlockEvent::Blocked { .. } => {}
+            BlockEvent::Cancelled { frame, handle } if handle == local_id.0 => {
+                if indicator.frame == Some(frame) {
+                    text.sections[0].style.color.set_a(0.0);
+                    indicator.frame = None;
+                }
+            }
+            BlockEvent::Cancelled { .. } => {}
+        }
     }
 }
 
@@ -310,14 +350,15 @@ fn move_caret(
     let local_player = local_player.single();
     if let Some(current_attack) = &remote_player.current_attack {
         let start_offset = remote_player.attack_start_time;
-        let impact_offset = start_offset + current_attack.startup_time;
-        let now = FrameOffset::now(&last_tick_time);
-        let offset = now.get_offset_seconds(&impact_offset);
+        let rate = last_tick_time.frame_rate;
+        let impact_offset = start_offset.after(current_attack.startup_time, rate);
+        let now = last_tick_time.now();
+        let offset = now.get_offset_seconds(&impact_offset, rate);
 
-        style.position.left = Val::Percent(50.0 + offset.0 as f32 * 100.0);
+        style.position.left = Val::Percent(50.0 + offset.as_secs_f32() * 100.0);
     } else {
-        style.position.left =
-            Val::Percent(50.0 + (Inorm64(local_player.last_defend_result).to_f32() * 100.0))
+        let last_defend_result = local_player.last_defend_result.as_secs_f32();
+        style.position.left = Val::Percent(50.0 + last_defend_result.clamp(-1.0, 1.0) * 100.0)
     }
 }
 
@@ -332,14 +373,15 @@ fn move_remote_caret(
     let local_player = local_player.single();
     if let Some(current_attack) = &local_player.current_attack {
         let start_offset = local_player.attack_start_time;
-        let impact_offset = start_offset + current_attack.startup_time;
-        let now = FrameOffset::now(&last_tick_time);
-        let offset = now.get_offset_seconds(&impact_offset);
+        let rate = last_tick_time.frame_rate;
+        let impact_offset = start_offset.after(current_attack.startup_time, rate);
+        let now = last_tick_time.now();
+        let offset = now.get_offset_seconds(&impact_offset, rate);
 
-        style.position.left = Val::Percent(50.0 + offset.0 as f32 * 100.0);
+        style.position.left = Val::Percent(50.0 + offset.as_secs_f32() * 100.0);
     } else {
-        style.position.left =
-            Val::Percent(50.0 + (Inorm64(remote_player.last_defend_result).to_f32() * 100.0))
+        let last_defend_result = remote_player.last_defend_result.as_secs_f32();
+        style.position.left = Val::Percent(50.0 + last_defend_result.clamp(-1.0, 1.0) * 100.0)
     }
 }
 
@@ -350,9 +392,10 @@ fn move_caret_final_clash(
 ) {
     let mut style = style_query.single_mut();
     if let Some(next_clash) = final_clash.next_clash{
-        let now = FrameOffset::now(&last_tick_time);
+        let now = last_tick_time.now();
+        let offset = now.get_offset_seconds(&next_clash, last_tick_time.frame_rate);
         style.position.left =
-            Val::Percent(50.0 + (now.get_offset_seconds(&next_clash).0 * 100.0 )as f32)
+            Val::Percent(50.0 + offset.as_secs_f32() * 100.0)
 
     }
 }
@@ -391,6 +434,138 @@ fn update_state_viewer(
 ){
     text_query.single_mut().sections[0].value = format!("{:?}", game_state);
 }
+fn setup_connection_status(mut commands: Commands, roboto: Res<Roboto>){
+    commands.spawn(TextBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font: roboto.0.clone(),
+                font_size: 32.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_alignment(TextAlignment::Center),
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                top: Val::Percent(5.0),
+                left: Val::Percent(30.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    }).insert(ConnectionStatusText);
+}
+
+fn update_connection_status(
+    connection_status: Res<ConnectionStatus>,
+    mut text_query: Query<&mut Text, With<ConnectionStatusText>>,
+){
+    text_query.single_mut().sections[0].value = match *connection_status {
+        ConnectionStatus::Synchronizing { progress: None } => "Connecting...".into(),
+        ConnectionStatus::Synchronizing { progress: Some((count, total)) } => format!("Connecting {count}/{total}..."),
+        ConnectionStatus::Connected => "".into(),
+        ConnectionStatus::Interrupted => "Connection interrupted...".into(),
+        ConnectionStatus::Disconnected => "Disconnected. Press Esc to quit".into(),
+    };
+}
+
+fn setup_lag_indicator(mut commands: Commands, roboto: Res<Roboto>){
+    commands.spawn(TextBundle {
+        text: Text::from_section(
+            "Opponent lagging",
+            TextStyle {
+                font: roboto.0.clone(),
+                font_size: 24.0,
+                color: Color::rgba(1.0, 0.6, 0.2, 0.0),
+            },
+        ),
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                top: Val::Percent(12.0),
+                left: Val::Percent(30.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    }).insert(LagIndicator);
+}
+
+/// Shows the warning whenever GGRS recommends waiting for the other side, fading out once
+/// it stops.
+fn update_lag_indicator(
+    time: Res<Time>,
+    mut ev_session: EventReader<SessionEvent>,
+    mut text_query: Query<&mut Text, With<LagIndicator>>,
+){
+    let mut text = text_query.single_mut();
+    let style = &mut text.sections[0].style;
+    if ev_session.iter().any(|event| matches!(event.0, GGRSEvent::WaitRecommendation { .. })) {
+        style.color.set_a(1.0);
+    } else {
+        style.color.set_a((style.color.a() - time.delta_seconds()).max(0.0));
+    }
+}
+
+fn setup_net_stats_panel(mut commands: Commands, roboto: Res<Roboto>){
+    commands.spawn(TextBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font: roboto.0.clone(),
+                font_size: 20.0,
+                color: Color::WHITE,
+            },
+        ),
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                bottom: Val::Percent(2.0),
+                left: Val::Percent(2.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        visibility: Visibility::Hidden,
+        ..Default::default()
+    }).insert(NetStatsPanel);
+}
+
+/// F3 shows and hides the network stats.
+fn toggle_net_stats_panel(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut panel_query: Query<&mut Visibility, With<NetStatsPanel>>,
+){
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        let mut visibility = panel_query.single_mut();
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
+
+fn update_net_stats_panel(
+    net_stats: Res<NetStats>,
+    mut text_query: Query<&mut Text, With<NetStatsPanel>>,
+){
+    let network = match &net_stats.network {
+        Some(network) => format!(
+            "Ping: {} ms\nFrames behind: {} local, {} remote\nSend queue: {}\nSending: {} kbps\n",
+            network.ping, network.local_frames_behind, network.remote_frames_behind, network.send_queue_len, network.kbps_sent,
+        ),
+        None => "No connection stats\n".into(),
+    };
+    let rejected = net_stats.rejected_packets;
+    text_query.single_mut().sections[0].value = format!(
+        "{network}Rollbacks: {} per second, deepest {} frames\nRejected packets: {} forged, {} replayed",
+        net_stats.rollbacks_per_second(), net_stats.deepest_rollback, rejected.forged, rejected.replayed,
+    );
+}
+
 fn setup_final_clash_lives(mut commands: Commands, roboto: Res<Roboto>){
 
     commands